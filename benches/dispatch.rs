@@ -0,0 +1,157 @@
+use advent_of_code_2019::intcode::asm::assemble;
+use advent_of_code_2019::intcode::network::{Action, Endpoint, Network, Packet};
+use advent_of_code_2019::intcode::IntCodeState;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+// Stands in for the day 19 drone: reads x then y, and outputs whether
+// 7y <= 10x <= 9y. Multiplies by repeated addition in a function called
+// the way compiled puzzle programs call theirs, so it runs a few hundred
+// instructions per point.
+const BEAM: &str = "
+            arb #stack
+            in x
+            in y
+            add x, #0, @1
+            add #10, #0, @2
+            add #r1, #0, @0
+            jt #1, #mul
+    r1:     add @3, #0, lhs
+            add y, #0, @1
+            add #7, #0, @2
+            add #r2, #0, @0
+            jt #1, #mul
+    r2:     add @3, #0, lo
+            add y, #0, @1
+            add #9, #0, @2
+            add #r3, #0, @0
+            jt #1, #mul
+    r3:     add @3, #0, hi
+            lt lhs, lo, t
+            jt t, #no
+            lt hi, lhs, t
+            jt t, #no
+            out #1
+            hlt
+    no:     out #0
+            hlt
+    mul:    add #0, #0, @3
+    loop:   jf @2, #done
+            add @3, @1, @3
+            add @2, #-1, @2
+            jt #1, #loop
+    done:   jt #1, @0
+    x:      data 0
+    y:      data 0
+    lhs:    data 0
+    lo:     data 0
+    hi:     data 0
+    t:      data 0
+    stack:  zeros 4
+";
+
+// Stands in for a day 23 NIC: node 0 starts a packet (0, HOPS) round the
+// ring, each node passing it on with x one more and hops one less, until
+// it runs out of hops and goes to 255
+const RING: &str = "
+            in addr
+            jt addr, #wait
+            out #1
+            out #0
+            out #HOPS
+    wait:   in x
+            eq x, #-1, t
+            jt t, #wait
+            in y
+            jf y, #done
+            add addr, #1, dest
+            eq dest, #NODES, t
+            jf t, #send
+            add #0, #0, dest
+    send:   out dest
+            add x, #1, x
+            out x
+            add y, #-1, y
+            out y
+            jt #1, #wait
+    done:   out #255
+            out x
+            out y
+            jt #1, #wait
+    addr:   data 0
+    x:      data 0
+    y:      data 0
+    t:      data 0
+    dest:   data 0
+";
+
+const NODES: usize = 50;
+const HOPS: i64 = 2000;
+
+// Day 19 runs a fresh copy of the drone program for every point
+fn scan(template: &IntCodeState) -> usize {
+    let mut count = 0;
+    for y in 0..50 {
+        for x in 0..50 {
+            let mut prog = template.clone();
+            let mut inputs = vec![y, x];
+            prog.execute_until_halt(|_| inputs.pop());
+            count += (prog.out_buffer.pop_front() == Some(1)) as usize;
+        }
+    }
+    count
+}
+
+struct Exit;
+
+impl Endpoint for Exit {
+    fn receive(&mut self, packet: &Packet) -> Action {
+        Action::Stop(packet.payload[0])
+    }
+}
+
+fn relay(template: &IntCodeState) -> i64 {
+    let mut network = Network::from_template(template, NODES);
+    network.connect(255, Exit);
+    network.run().unwrap()
+}
+
+// The same program with the handler cache used as the days use it, and
+// with every instruction decoded each time it runs
+fn variants(source: &str) -> [(&'static str, IntCodeState); 3] {
+    let cached: IntCodeState = assemble(source).unwrap().into();
+    let mut predecoded = cached.clone();
+    predecoded.predecode();
+    let mut decoded = cached.clone();
+    decoded.disable_decode_cache();
+    [
+        ("cached", cached),
+        ("predecoded", predecoded),
+        ("decoded every step", decoded),
+    ]
+}
+
+fn day_19(c: &mut Criterion) {
+    let mut group = c.benchmark_group("day 19 scan");
+    for (name, template) in variants(BEAM) {
+        assert_eq!(scan(&template), 250);
+        group.bench_with_input(BenchmarkId::from_parameter(name), &template, |b, t| {
+            b.iter(|| scan(black_box(t)))
+        });
+    }
+    group.finish();
+}
+
+fn day_23(c: &mut Criterion) {
+    let source = format!("const NODES = {}\nconst HOPS = {}\n{}", NODES, HOPS, RING);
+    let mut group = c.benchmark_group("day 23 network");
+    for (name, template) in variants(&source) {
+        assert_eq!(relay(&template), HOPS);
+        group.bench_with_input(BenchmarkId::from_parameter(name), &template, |b, t| {
+            b.iter(|| relay(black_box(t)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, day_19, day_23);
+criterion_main!(benches);
@@ -1,3 +1,4 @@
+use advent_of_code_2019::intcode::symbolic::{Linear, PathEnd, SymbolicLimits, SymbolicState, Var};
 use advent_of_code_2019::intcode::{parse_intcode_to_vec, IntCodeState};
 use advent_of_code_2019::{Cli, Parser};
 use std::fs;
@@ -17,10 +18,48 @@ fn calculate_p1(nums: &[i64]) -> i64 {
     calculate(nums, 12, 2)
 }
 
+const TARGET: i64 = 19690720;
+
+// mem[0] as a formula of the noun and verb, if it's linear in them
+fn result_formula(nums: &[i64]) -> Option<Linear> {
+    let mut state = SymbolicState::new(nums.to_vec());
+    state.symbolic_mem(1);
+    state.symbolic_mem(2);
+    let paths = state.explore(&SymbolicLimits::default());
+    match &paths[..] {
+        [path] if path.end == PathEnd::Halted => path.get_mem(0).linear(),
+        _ => None,
+    }
+}
+
 fn calculate_p2(nums: &[i64]) -> i64 {
+    // Solve for the noun given each verb, rather than running every pair,
+    // falling back to running them all if that finds nothing
+    if let Some(formula) = result_formula(nums) {
+        let noun_coefficient = formula.coefficient(Var::Memory(1));
+        let verb_coefficient = formula.coefficient(Var::Memory(2));
+        for verb in 0..100 {
+            let Some(remaining) = verb_coefficient
+                .checked_mul(verb)
+                .and_then(|v| TARGET.checked_sub(formula.constant)?.checked_sub(v))
+            else {
+                continue;
+            };
+            let noun = match noun_coefficient {
+                0 if remaining == 0 => 0,
+                0 => continue,
+                c if remaining % c == 0 => remaining / c,
+                _ => continue,
+            };
+            if (0..100).contains(&noun) && calculate(nums, noun, verb) == TARGET {
+                return 100 * noun + verb;
+            }
+        }
+    }
+
     for verb in 0..100 {
         for noun in 0..100 {
-            if calculate(nums, noun, verb) == 19690720 {
+            if calculate(nums, noun, verb) == TARGET {
                 return 100 * noun + verb;
             }
         }
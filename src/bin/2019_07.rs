@@ -1,5 +1,8 @@
 use advent_of_code_2019::intcode::{parse_intcode_to_vec, IntCodeState};
 use advent_of_code_2019::{Cli, Parser};
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::future::join_all;
 use itertools::Itertools;
 use rayon::prelude::*;
 use std::fs;
@@ -29,31 +32,33 @@ fn calculate_p1(software: &[i64]) -> i64 {
 }
 
 fn run_amplifiers_p2(software: &[i64], phases: &[i64]) -> i64 {
-    let mut amp1: IntCodeState = software.into();
-    let mut amp2: IntCodeState = software.into();
-    let mut amp3: IntCodeState = software.into();
-    let mut amp4: IntCodeState = software.into();
-    let mut amp5: IntCodeState = software.into();
-
-    let mut a1_inputs = vec![0, phases[0]];
-    let mut a2_inputs = vec![phases[1]];
-    let mut a3_inputs = vec![phases[2]];
-    let mut a4_inputs = vec![phases[3]];
-    let mut a5_inputs = vec![phases[4]];
-
-    loop {
-        amp1.execute_single_step(|_| a1_inputs.pop().or(amp5.out_buffer.pop_front()));
-        amp2.execute_single_step(|_| a2_inputs.pop().or(amp1.out_buffer.pop_front()));
-        amp3.execute_single_step(|_| a3_inputs.pop().or(amp2.out_buffer.pop_front()));
-        amp4.execute_single_step(|_| a4_inputs.pop().or(amp3.out_buffer.pop_front()));
-        let halt = amp5.execute_single_step(|_| a5_inputs.pop().or(amp4.out_buffer.pop_front()));
-
-        if halt {
-            break;
-        }
+    // Each amplifier reads from its own channel and writes to the next
+    // one's, with the last feeding back into the first
+    let (senders, mut receivers): (Vec<_>, Vec<_>) = phases
+        .iter()
+        .map(|&phase| {
+            let (tx, rx) = mpsc::unbounded();
+            tx.unbounded_send(phase).expect("receiver exists");
+            (tx, rx)
+        })
+        .unzip();
+    senders[0].unbounded_send(0).expect("receiver exists");
+
+    let amps = receivers
+        .iter_mut()
+        .zip(senders.iter().cycle().skip(1))
+        .map(|(input, output)| async move {
+            let mut amp: IntCodeState = software.into();
+            amp.run_async(input, output).await;
+        });
+    block_on(join_all(amps));
+
+    // The last amplifier's final output is left waiting for the first one
+    let mut last_output = None;
+    while let Ok(out) = receivers[0].try_recv() {
+        last_output = Some(out);
     }
-
-    amp5.out_buffer.pop_front().expect("p2: no solution")
+    last_output.expect("p2: no solution")
 }
 
 fn calculate_p2(software: &[i64]) -> i64 {
@@ -3,7 +3,7 @@ use advent_of_code_2019::{Cli, Parser};
 use std::fs;
 
 fn calculate<const MODE: i64>(software: &str) -> i64 {
-    let mut prog: IntCodeState<2048> = software.into();
+    let mut prog: IntCodeState = software.into();
     prog.execute_until_halt(|_| Some(MODE));
     prog.out_buffer.pop_back().unwrap()
 }
@@ -1,4 +1,4 @@
-use advent_of_code_2019::intcode::IntCodeState;
+use advent_of_code_2019::intcode::{IntCodeState, IntcodeIo};
 use advent_of_code_2019::{Cli, Parser};
 use ahash::AHashMap;
 use itertools::Itertools;
@@ -6,42 +6,58 @@ use std::fs;
 
 const DIRS: [(i64, i64); 4] = [(0, 1), (1, 0), (0, -1), (-1, 0)];
 
-fn paint<const INITIAL_TILE: bool>(software: &str) -> AHashMap<(i64, i64), bool> {
-    let mut prog: IntCodeState<2048> = software.into();
+struct Robot {
+    painted: AHashMap<(i64, i64), bool>,
+    x: i64,
+    y: i64,
+    dir: i64,
+    colour: Option<i64>,
+}
 
-    let mut painted: AHashMap<(i64, i64), bool> = AHashMap::default();
+impl IntcodeIo for Robot {
+    fn input(&mut self, _: &mut IntCodeState) -> Option<i64> {
+        if *self.painted.get(&(self.x, self.y)).unwrap_or(&false) {
+            Some(1)
+        } else {
+            Some(0)
+        }
+    }
 
-    let mut x = 0;
-    let mut y = 0;
-    let mut dir: i64 = 0;
+    fn output(&mut self, _: &mut IntCodeState, value: i64) {
+        // Outputs come in pairs of colour then direction
+        let Some(colour) = self.colour.take() else {
+            self.colour = Some(value);
+            return;
+        };
 
-    painted.insert((0, 0), INITIAL_TILE);
+        if value == 1 {
+            self.dir += 1;
+        } else {
+            self.dir -= 1;
+        }
 
-    prog.execute_until_halt(|state| {
-        if !state.out_buffer.is_empty() {
-            let colour = state.out_buffer.pop_front().expect("missing colour");
-            let dir_instruction = state.out_buffer.pop_front().expect("missing dir");
+        self.painted.insert((self.x, self.y), colour == 1);
 
-            if dir_instruction == 1 {
-                dir += 1;
-            } else {
-                dir -= 1;
-            }
+        self.x += DIRS[self.dir.rem_euclid(4) as usize].0;
+        self.y += DIRS[self.dir.rem_euclid(4) as usize].1;
+    }
+}
 
-            let paint = colour == 1;
-            painted.insert((x, y), paint);
+fn paint<const INITIAL_TILE: bool>(software: &str) -> AHashMap<(i64, i64), bool> {
+    let mut prog: IntCodeState = software.into();
 
-            x += DIRS[dir.rem_euclid(4) as usize].0;
-            y += DIRS[dir.rem_euclid(4) as usize].1;
-        }
-        if *painted.get(&(x, y)).unwrap_or(&false) {
-            Some(1)
-        } else {
-            Some(0)
-        }
-    });
+    let mut robot = Robot {
+        painted: AHashMap::default(),
+        x: 0,
+        y: 0,
+        dir: 0,
+        colour: None,
+    };
+    robot.painted.insert((0, 0), INITIAL_TILE);
+
+    prog.run_io(&mut robot);
 
-    painted
+    robot.painted
 }
 
 fn calculate_p1(software: &str) -> usize {
@@ -1,4 +1,4 @@
-use advent_of_code_2019::intcode::IntCodeState;
+use advent_of_code_2019::intcode::{IntCodeEvent, IntCodeState};
 use advent_of_code_2019::{Cli, Parser};
 use ahash::AHashSet;
 use std::cmp::Ordering;
@@ -7,7 +7,7 @@ use std::fs;
 fn calculate_p1(software: &str) -> usize {
     let mut blocks: AHashSet<(i64, i64)> = AHashSet::with_capacity(1024);
 
-    let mut prog: IntCodeState<4096> = software.into();
+    let mut prog: IntCodeState = software.into();
     prog.execute_until_halt_no_input();
 
     while !prog.out_buffer.is_empty() {
@@ -27,29 +27,33 @@ fn calculate_p1(software: &str) -> usize {
 fn calculate_p2(software: &str) -> i64 {
     let mut blocks: AHashSet<(i64, i64)> = AHashSet::with_capacity(1024);
 
-    let mut prog: IntCodeState<4096> = software.into();
+    let mut prog: IntCodeState = software.into();
     prog.set_mem(0, 2);
 
     let mut ball_x = 0;
     let mut paddle_x = 0;
 
+    let mut packet = Vec::with_capacity(3);
+
     loop {
-        while prog.out_buffer.len() < 3 {
-            prog.execute_single_step(|_| {
-                Some(match paddle_x.cmp(&ball_x) {
-                    Ordering::Less => 1,
-                    Ordering::Equal => 0,
-                    Ordering::Greater => -1,
-                })
-            });
+        match prog.run_until_event() {
+            IntCodeEvent::NeedsInput => prog.in_buffer.push_back(match paddle_x.cmp(&ball_x) {
+                Ordering::Less => 1,
+                Ordering::Equal => 0,
+                Ordering::Greater => -1,
+            }),
+            IntCodeEvent::Output(out) => packet.push(out),
+            IntCodeEvent::Halted => panic!("p2: game ended with blocks remaining"),
         }
 
-        let x = prog.out_buffer.pop_front().expect("x should exist");
-        let y = prog.out_buffer.pop_front().expect("y should exist");
-        let tile_or_score = prog
-            .out_buffer
-            .pop_front()
-            .expect("tile or score should exist");
+        if packet.len() < 3 {
+            continue;
+        }
+
+        let x = packet[0];
+        let y = packet[1];
+        let tile_or_score = packet[2];
+        packet.clear();
 
         if x == -1 {
             if blocks.is_empty() {
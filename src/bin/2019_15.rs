@@ -1,4 +1,4 @@
-use advent_of_code_2019::intcode::IntCodeState;
+use advent_of_code_2019::intcode::{IntCodeState, IntcodeIo};
 use advent_of_code_2019::{Cli, Parser};
 use ahash::AHashMap;
 use std::collections::BinaryHeap;
@@ -75,65 +75,75 @@ fn try_move(
     }
 }
 
-fn calculate(software: &str) -> (i64, i64) {
-    let mut prog: IntCodeState = software.into();
-
-    let mut known: AHashMap<(i64, i64), Space> = AHashMap::with_capacity(1024);
-    known.insert((0, 0), Space::Empty);
-
-    let mut x: i64 = 0;
-    let mut y: i64 = 0;
-    let mut dir: usize = DIR_NORTH;
-    let mut sensor_pos = None;
-    let mut fully_explored = false;
-
-    while !fully_explored {
-        prog.execute_single_step(|s| {
-            if let Some(output) = s.out_buffer.pop_front() {
-                if output == 1 || output == 2 {
-                    x += DIRS[dir].0;
-                    y += DIRS[dir].1;
-                    known.insert((x, y), Space::Empty);
-                    if output == 2 {
-                        sensor_pos = Some((x, y));
-                    }
-                } else if output == 0 {
-                    known.insert((x + DIRS[dir].0, y + DIRS[dir].1), Space::Wall);
-                }
+struct Droid {
+    known: AHashMap<(i64, i64), Space>,
+    x: i64,
+    y: i64,
+    dir: usize,
+    sensor_pos: Option<(i64, i64)>,
+}
 
-                let north = *known.get(&(x, y + 1)).unwrap_or(&Space::Unknown);
-                let south = *known.get(&(x, y - 1)).unwrap_or(&Space::Unknown);
-                let east = *known.get(&(x + 1, y)).unwrap_or(&Space::Unknown);
-                let west = *known.get(&(x - 1, y)).unwrap_or(&Space::Unknown);
+impl IntcodeIo for Droid {
+    // Picks the next move, or stops once everything has been explored
+    fn input(&mut self, _: &mut IntCodeState) -> Option<i64> {
+        let (x, y) = (self.x, self.y);
+        let north = *self.known.get(&(x, y + 1)).unwrap_or(&Space::Unknown);
+        let south = *self.known.get(&(x, y - 1)).unwrap_or(&Space::Unknown);
+        let east = *self.known.get(&(x + 1, y)).unwrap_or(&Space::Unknown);
+        let west = *self.known.get(&(x - 1, y)).unwrap_or(&Space::Unknown);
+
+        if let Some((d, command)) = try_move(north, south, east, west, Space::Unknown) {
+            self.dir = d;
+            return Some(command);
+        }
 
-                if let Some((d, command)) = try_move(north, south, east, west, Space::Unknown) {
-                    dir = d;
-                    return Some(command);
-                }
+        let wall_count = [north, south, east, west]
+            .iter()
+            .filter(|&x| *x == Space::Wall || *x == Space::DeadEnd)
+            .count();
 
-                let wall_count = [north, south, east, west]
-                    .iter()
-                    .filter(|&x| *x == Space::Wall || *x == Space::DeadEnd)
-                    .count();
+        if wall_count == 3 {
+            self.known.insert((x, y), Space::DeadEnd);
+        }
 
-                if wall_count == 3 {
-                    known.insert((x, y), Space::DeadEnd);
-                }
+        if let Some((d, command)) = try_move(north, south, east, west, Space::Empty) {
+            self.dir = d;
+            return Some(command);
+        }
 
-                if let Some((d, command)) = try_move(north, south, east, west, Space::Empty) {
-                    dir = d;
-                    return Some(command);
-                }
+        None
+    }
 
-                fully_explored = true;
-                None
-            } else {
-                Some(COMMAND_NORTH)
+    fn output(&mut self, _: &mut IntCodeState, status: i64) {
+        let (dx, dy) = DIRS[self.dir];
+        if status == 1 || status == 2 {
+            self.x += dx;
+            self.y += dy;
+            self.known.insert((self.x, self.y), Space::Empty);
+            if status == 2 {
+                self.sensor_pos = Some((self.x, self.y));
             }
-        });
+        } else if status == 0 {
+            self.known.insert((self.x + dx, self.y + dy), Space::Wall);
+        }
     }
+}
+
+fn calculate(software: &str) -> (i64, i64) {
+    let mut prog: IntCodeState = software.into();
+
+    let mut droid = Droid {
+        known: AHashMap::with_capacity(1024),
+        x: 0,
+        y: 0,
+        dir: DIR_NORTH,
+        sensor_pos: None,
+    };
+    droid.known.insert((0, 0), Space::Empty);
+
+    prog.run_io(&mut droid);
 
-    dijkstra(&known, sensor_pos.unwrap())
+    dijkstra(&droid.known, droid.sensor_pos.unwrap())
 }
 
 fn main() {
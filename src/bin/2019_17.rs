@@ -1,10 +1,8 @@
 use advent_of_code_2019::intcode::IntCodeState;
 use advent_of_code_2019::{Cli, Parser};
-use itertools::intersperse;
 use itertools::Itertools;
 use ndarray::Array2;
 use std::cmp::{max, min};
-use std::collections::VecDeque;
 use std::fs;
 use std::iter::zip;
 
@@ -190,19 +188,14 @@ fn program_from_fragments(path: &[&str], a: &[&str], b: &[&str], c: &[&str]) ->
 }
 
 fn calculate(software: &str) -> (usize, i64) {
-    let mut prog_p1: IntCodeState<4096> = software.into();
+    let mut prog_p1: IntCodeState = software.into();
 
-    prog_p1.execute_until_halt_no_input();
+    let camera = prog_p1.ascii().read_until_prompt();
+    assert!(camera.halted, "should not ask for input");
 
-    let view = parse_map(
-        prog_p1
-            .out_buffer
-            .iter()
-            .map(|&c| u8::try_from(c).expect("invalid char"))
-            .collect::<Vec<u8>>(),
-    );
+    let view = parse_map(camera.text.into_bytes());
 
-    let mut prog_p2: IntCodeState<4096> = software.into();
+    let mut prog_p2: IntCodeState = software.into();
 
     let path = get_path(&view);
 
@@ -210,26 +203,17 @@ fn calculate(software: &str) -> (usize, i64) {
 
     let prog = program_from_fragments(&path, &a, &b, &c);
 
-    let mut p2_prog_inputs = intersperse(prog.iter(), &",")
-        .chain(Some(&"\n"))
-        .chain(intersperse(a.iter(), &","))
-        .chain(Some(&"\n"))
-        .chain(intersperse(b.iter(), &","))
-        .chain(Some(&"\n"))
-        .chain(intersperse(c.iter(), &","))
-        .chain(Some(&"\n"))
-        .chain(Some(&"n"))
-        .chain(Some(&"\n"))
-        .flat_map(|s| s.bytes())
-        .map(i64::from)
-        .collect::<VecDeque<_>>();
-
     prog_p2.set_mem(0, 2);
-    prog_p2.execute_until_halt(|_| p2_prog_inputs.pop_front());
+    let mut ascii = prog_p2.ascii();
+    for routine in [prog, a, b, c] {
+        ascii.send_line(&routine.join(","));
+    }
+    ascii.send_line("n");
+    let output = ascii.read_until_prompt();
 
     (
         calculate_p1(&view),
-        prog_p2.out_buffer.pop_back().expect("no output p2"),
+        *output.values.last().expect("no output p2"),
     )
 }
 
@@ -4,8 +4,14 @@ use rayon::prelude::*;
 use std::cmp::max;
 use std::fs;
 
-fn is_in_beam(software: &[i64], x: usize, y: usize) -> bool {
-    let mut prog: IntCodeState<512> = software.into();
+fn predecoded(software: &[i64]) -> IntCodeState {
+    let mut prog: IntCodeState = software.into();
+    prog.predecode();
+    prog
+}
+
+fn is_in_beam(template: &IntCodeState, x: usize, y: usize) -> bool {
+    let mut prog = template.clone();
     let mut inputs = vec![y as i64, x as i64];
 
     prog.execute_until_halt(|_| inputs.pop());
@@ -16,25 +22,27 @@ fn is_in_beam(software: &[i64], x: usize, y: usize) -> bool {
 const SANTA_SHIP_SIZE: usize = 100;
 
 fn calculate_p1(software: &[i64]) -> usize {
+    let template = predecoded(software);
     (0..50)
         .into_par_iter()
         .map(|y| {
             (0..50)
                 .into_par_iter()
-                .filter(|&x| is_in_beam(software, x, y))
+                .filter(|&x| is_in_beam(&template, x, y))
                 .count()
         })
         .sum()
 }
 
 fn calculate_p2(software: &[i64]) -> usize {
+    let template = predecoded(software);
     let mut starts_ends: Vec<(usize, usize)> = vec![];
     let mut y: usize = 0;
 
     loop {
         let (mut start, mut end) = if y == 0 { (0, 0) } else { starts_ends[y - 1] };
 
-        while !is_in_beam(software, start, y) {
+        while !is_in_beam(&template, start, y) {
             start += 1;
 
             // Annoying breakout if the beam isn't detected at all on
@@ -48,7 +56,7 @@ fn calculate_p2(software: &[i64]) -> usize {
 
         end = max(start + 1, end);
 
-        while is_in_beam(software, end, y) {
+        while is_in_beam(&template, end, y) {
             end += 1;
         }
 
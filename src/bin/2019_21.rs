@@ -1,16 +1,20 @@
 use advent_of_code_2019::intcode::IntCodeState;
 use advent_of_code_2019::{Cli, Parser};
-use std::collections::VecDeque;
 use std::fs;
 
 fn run_with_logic(software: &str, logic: &str) -> i64 {
-    let mut prog: IntCodeState<4096> = software.into();
+    let mut prog: IntCodeState = software.into();
+    let mut ascii = prog.ascii();
 
-    let mut inputs = logic.bytes().map(|x| x as i64).collect::<VecDeque<_>>();
+    logic.lines().for_each(|line| ascii.send_line(line));
+    let output = ascii.read_until_prompt();
 
-    prog.execute_until_halt(|_| inputs.pop_front());
-
-    prog.out_buffer.pop_back().expect("no output")
+    // The droid falling into a hole is drawn instead of giving an answer
+    output
+        .values
+        .last()
+        .copied()
+        .unwrap_or_else(|| panic!("no output:\n{}", output.text))
 }
 
 fn calculate_p1(software: &str) -> i64 {
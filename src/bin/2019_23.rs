@@ -1,77 +1,56 @@
-use advent_of_code_2019::intcode::{parse_intcode_to_vec, IntCodeState};
+use advent_of_code_2019::intcode::network::{Action, Endpoint, Network, Packet};
+use advent_of_code_2019::intcode::parse_intcode_to_vec;
 use advent_of_code_2019::{Cli, Parser};
-use std::collections::VecDeque;
 use std::fs;
 
-fn calculate<const PART: u8>(software: &[i64]) -> i64 {
-    let mut nics = (0..50)
-        .map(|_| software.into())
-        .collect::<Vec<IntCodeState<4096>>>();
-
-    let mut input_buffers = (0..50)
-        .map(|i| {
-            let mut q = VecDeque::with_capacity(16);
-            q.push_back(i);
-            q
-        })
-        .collect::<Vec<_>>();
-
-    let mut last_nat_y = None;
-
-    let mut nat_x = None;
-    let mut nat_y = None;
+// Stops at the first packet sent to it
+struct FirstPacket;
 
-    loop {
-        let mut all_waiting_for_input = true;
-
-        for (i, nic) in nics.iter_mut().enumerate() {
-            let mut waiting_for_input = false;
-
-            while !waiting_for_input {
-                nic.execute_single_step(|_| {
-                    if let Some(inp) = input_buffers[i].pop_front() {
-                        Some(inp)
-                    } else {
-                        waiting_for_input = true;
-                        Some(-1)
-                    }
-                });
-            }
+impl Endpoint for FirstPacket {
+    fn receive(&mut self, packet: &Packet) -> Action {
+        Action::Stop(packet.payload[1])
+    }
+}
 
-            all_waiting_for_input = all_waiting_for_input && waiting_for_input;
+// Wakes the network up with the last packet sent to it, stopping if it
+// sends the same Y value twice in a row
+#[derive(Default)]
+struct Nat {
+    packet: Option<Vec<i64>>,
+    last_y: Option<i64>,
+}
 
-            while nic.out_buffer.len() >= 3 {
-                let dest = nic.out_buffer.pop_front().unwrap();
-                let x = nic.out_buffer.pop_front().unwrap();
-                let y = nic.out_buffer.pop_front().unwrap();
+impl Endpoint for Nat {
+    fn receive(&mut self, packet: &Packet) -> Action {
+        self.packet = Some(packet.payload.clone());
+        Action::Continue
+    }
 
-                if dest == 255 {
-                    if PART == 1 {
-                        return y;
-                    } else {
-                        nat_x = Some(x);
-                        nat_y = Some(y);
-                    }
-                } else {
-                    input_buffers[dest as usize].push_back(x);
-                    input_buffers[dest as usize].push_back(y);
-                }
-            }
+    fn idle(&mut self) -> Action {
+        let Some(packet) = self.packet.clone() else {
+            return Action::Continue;
+        };
+        if Some(packet[1]) == self.last_y {
+            return Action::Stop(packet[1]);
         }
-
-        if PART == 2 && all_waiting_for_input && input_buffers.iter().all(|b| b.is_empty()) {
-            if let Some(nat_y) = nat_y {
-                if Some(nat_y) == last_nat_y {
-                    return nat_y;
-                }
-                input_buffers[0].push_back(nat_x.expect("nat has no packet"));
-                input_buffers[0].push_back(nat_y);
-                last_nat_y = Some(nat_y);
-            }
+        self.last_y = Some(packet[1]);
+        Action::Send {
+            address: 0,
+            payload: packet,
         }
     }
 }
 
+fn calculate<const PART: u8>(software: &[i64]) -> i64 {
+    let mut network = Network::new(software, 50);
+    if PART == 1 {
+        network.connect(255, FirstPacket);
+    } else {
+        network.connect(255, Nat::default());
+    }
+    network.run().unwrap_or_else(|e| panic!("{}", e))
+}
+
 fn main() {
     let args = Cli::parse();
 
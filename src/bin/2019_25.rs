@@ -1,4 +1,5 @@
-use advent_of_code_2019::intcode::IntCodeState;
+use advent_of_code_2019::intcode::ascii::{decode, encode_line};
+use advent_of_code_2019::intcode::{IntCodeState, Limits, RunOutcome};
 use advent_of_code_2019::{Cli, Parser};
 use itertools::Itertools;
 use std::collections::VecDeque;
@@ -64,11 +65,7 @@ fn explore(
         .iter()
         .take(1) // There is only one item per room that we want to take.
         .for_each(|item| {
-            "take "
-                .bytes()
-                .chain(item.bytes())
-                .chain(Some(b'\n'))
-                .for_each(|b| inp_buffer.push_back(b as i64));
+            inp_buffer.extend(encode_line(&format!("take {}", item)));
 
             inventory.push(item.to_string());
         });
@@ -76,10 +73,7 @@ fn explore(
     for dir_diff in 1..=4 {
         let next_dir = (*current_dir + dir_diff + 2).rem_euclid(4);
         if allowed_dirs.contains(&DIRS[next_dir]) {
-            DIRS[next_dir]
-                .bytes()
-                .chain(Some(b'\n'))
-                .for_each(|b| inp_buffer.push_back(b as i64));
+            inp_buffer.extend(encode_line(DIRS[next_dir]));
             *current_dir = next_dir;
             break;
         }
@@ -106,34 +100,18 @@ fn force_open_door(
         inventory
             .iter()
             .filter(|item| !s.contains(item))
-            .for_each(|item| {
-                "drop "
-                    .bytes()
-                    .chain(item.bytes())
-                    .chain(Some(b'\n'))
-                    .for_each(|b| inp_buffer.push_back(b as i64));
-            });
-
-        s.iter().for_each(|item| {
-            "take "
-                .bytes()
-                .chain(item.bytes())
-                .chain(Some(b'\n'))
-                .for_each(|b| inp_buffer.push_back(b as i64));
-        });
+            .for_each(|item| inp_buffer.extend(encode_line(&format!("drop {}", item))));
+
+        s.iter()
+            .for_each(|item| inp_buffer.extend(encode_line(&format!("take {}", item))));
 
-        santa_direction
-            .bytes()
-            .chain(Some(b'\n'))
-            .for_each(|b| inp_buffer.push_back(b as i64));
+        inp_buffer.extend(encode_line(santa_direction));
     }
 }
 
-fn final_out_buffer_to_answer<const T: usize>(prog: IntCodeState<T>) -> String {
-    prog.out_buffer
-        .iter()
-        .map(|&c| (c as u8) as char)
-        .join("")
+fn final_out_buffer_to_answer(prog: IntCodeState) -> String {
+    decode(prog.out_buffer)
+        .text
         .lines()
         .map(|line| line.trim())
         .filter_map(|line| {
@@ -146,7 +124,7 @@ fn final_out_buffer_to_answer<const T: usize>(prog: IntCodeState<T>) -> String {
 }
 
 fn calculate(software: &str) -> String {
-    let mut prog: IntCodeState<8192> = software.into();
+    let mut prog: IntCodeState = software.into();
     let mut inp_buffer = VecDeque::new();
 
     let mut finished_exploring = false;
@@ -154,18 +132,16 @@ fn calculate(software: &str) -> String {
     let mut inventory = vec![];
     let mut checkpoint_count = 0;
 
-    prog.execute_until_halt(|state| {
+    let limits = Limits {
+        detect_loops: true,
+        ..Default::default()
+    };
+    let outcome = prog.execute_with_limits(limits, |state| {
         if let Some(c) = inp_buffer.pop_front() {
             return Some(c);
         }
 
-        let mut out_vec = vec![];
-
-        while let Some(c) = state.out_buffer.pop_front() {
-            out_vec.push((c as u8) as char);
-        }
-
-        let out_str = out_vec.iter().join("");
+        let out_str = decode(state.out_buffer.drain(..)).text;
         let allowed_dirs = parse_allowed_dirs(&out_str);
 
         if !finished_exploring {
@@ -183,6 +159,13 @@ fn calculate(software: &str) -> String {
 
         inp_buffer.pop_front()
     });
+    // Taking a bad item hangs the game rather than halting it
+    if let RunOutcome::InfiniteLoop {
+        instruction_ptr, ..
+    } = outcome
+    {
+        panic!("game stuck in a loop at {}", instruction_ptr);
+    }
 
     final_out_buffer_to_answer(prog)
 }
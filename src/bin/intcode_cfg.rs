@@ -0,0 +1,13 @@
+use advent_of_code_2019::intcode::cfg::ControlFlowGraph;
+use advent_of_code_2019::intcode::parse_intcode_to_vec;
+use advent_of_code_2019::{Cli, Parser};
+use std::fs;
+
+fn main() {
+    let args = Cli::parse();
+
+    let inp = fs::read_to_string(args.input).expect("can't open input file");
+
+    let cfg = ControlFlowGraph::build(&parse_intcode_to_vec(&inp));
+    print!("{}", cfg.to_dot());
+}
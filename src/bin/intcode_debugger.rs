@@ -0,0 +1,515 @@
+use advent_of_code_2019::intcode::disasm::Opcode;
+use advent_of_code_2019::intcode::IntCodeState;
+use advent_of_code_2019::{Cli, Parser};
+use ahash::AHashSet;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+step [n]           execute n instructions (default 1)
+back [n]           undo the last n instructions (default 1)
+history on [n]     record the last n instructions for back (default 10000)
+history off        stop recording, discarding what was recorded
+continue           run until a breakpoint, watchpoint, input request or halt
+break <addr>       stop before executing the instruction at addr
+delete <addr>      remove a breakpoint
+watch <addr>       stop when the value at addr changes
+unwatch <addr>     remove a watchpoint
+info               show registers, breakpoints and watchpoints
+mem <addr> [n]     show n words of memory from addr (default 8)
+set <addr> <val>   write val to memory at addr
+ip [val]           show or set the instruction pointer
+base [val]         show or set the relative base
+dis [addr] [n]     disassemble n instructions from addr (default ip)
+in <vals...>       queue numeric input values
+ascii <text>       queue a line of text as ASCII input
+out [clear]        show or clear the output buffer
+display ascii|num  how output is shown while running
+profile start|stop start counting executed instructions, or stop and report
+profile [n]        report the n hottest addresses and loops so far (default 10)
+save <file>        write a snapshot of the program state to file
+load <file>        restore the program state from a snapshot
+quit               exit";
+
+// Number of instructions `back` can undo, unless told otherwise
+const HISTORY_DEPTH: usize = 10_000;
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+enum OutputDisplay {
+    Ascii,
+    Numeric,
+}
+
+struct Debugger {
+    prog: IntCodeState,
+    breakpoints: AHashSet<i64>,
+    watchpoints: BTreeMap<i64, i64>,
+    display: OutputDisplay,
+    halted: bool,
+    // Undo history is only recorded once asked for, as it slows running
+    history_depth: Option<usize>,
+}
+
+fn parse_num(arg: Option<&str>) -> Result<i64, String> {
+    let arg = arg.ok_or("missing argument")?;
+    arg.parse().map_err(|_| format!("invalid number '{}'", arg))
+}
+
+impl Debugger {
+    fn new(prog: IntCodeState) -> Self {
+        Debugger {
+            prog,
+            breakpoints: AHashSet::default(),
+            watchpoints: BTreeMap::new(),
+            display: OutputDisplay::Ascii,
+            halted: false,
+            history_depth: None,
+        }
+    }
+
+    fn format_output(&self, value: i64, out: &mut String) {
+        match (self.display, u8::try_from(value)) {
+            (OutputDisplay::Ascii, Ok(c)) if c.is_ascii() => out.push(c as char),
+            _ => writeln!(out, "[{}]", value).unwrap(),
+        }
+    }
+
+    fn current_instruction(&self) -> String {
+        match self.prog.decode_executable_at(self.prog.instruction_ptr()) {
+            Some(ins) => format!("{:>5}: {}", ins.address, ins),
+            None => format!("{:>5}: ???", self.prog.instruction_ptr()),
+        }
+    }
+
+    // After the program state changes other than by running forwards
+    fn resync(&mut self) {
+        self.halted = false;
+        for (&address, value) in self.watchpoints.iter_mut() {
+            *value = self.prog.try_get_mem(address).unwrap_or(0);
+        }
+    }
+
+    // Executes one instruction, returning why execution should stop, if it should
+    fn step(&mut self, out: &mut String) -> Option<String> {
+        if self.halted {
+            return Some("program has halted".to_string());
+        }
+
+        let blocked_on_input = self.prog.in_buffer.is_empty()
+            && self
+                .prog
+                .decode_executable_at(self.prog.instruction_ptr())
+                .is_some_and(|ins| ins.opcode == Opcode::In);
+        if blocked_on_input {
+            return Some("waiting for input".to_string());
+        }
+
+        let out_len = self.prog.out_buffer.len();
+        match self
+            .prog
+            .try_execute_single_step(|s| s.in_buffer.pop_front())
+        {
+            Ok(halted) => self.halted = halted,
+            Err(e) => return Some(format!("error: {}", e)),
+        }
+
+        for idx in out_len..self.prog.out_buffer.len() {
+            self.format_output(self.prog.out_buffer[idx], out);
+        }
+
+        for (&address, old) in self.watchpoints.iter_mut() {
+            let new = self.prog.try_get_mem(address).unwrap_or(0);
+            if new != *old {
+                let reason = format!("watchpoint {}: {} -> {}", address, old, new);
+                *old = new;
+                return Some(reason);
+            }
+        }
+
+        if self.halted {
+            Some("program halted".to_string())
+        } else if self.breakpoints.contains(&self.prog.instruction_ptr()) {
+            Some(format!("breakpoint {}", self.prog.instruction_ptr()))
+        } else {
+            None
+        }
+    }
+
+    fn run(&mut self, max_steps: Option<i64>) -> String {
+        let mut out = String::new();
+        let mut steps = 0;
+        let reason = loop {
+            if let Some(reason) = self.step(&mut out) {
+                break Some(reason);
+            }
+            steps += 1;
+            if Some(steps) == max_steps {
+                break None;
+            }
+        };
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push('\n');
+        }
+        if let Some(reason) = reason {
+            writeln!(out, "stopped: {}", reason).unwrap();
+        }
+        out.push_str(&self.current_instruction());
+        out
+    }
+
+    fn execute(&mut self, line: &str) -> Result<String, String> {
+        let mut args = line.split_whitespace();
+        let Some(command) = args.next() else {
+            return Ok(String::new());
+        };
+
+        match command {
+            "s" | "step" => {
+                let n = args.next().map_or(Ok(1), |a| parse_num(Some(a)))?;
+                Ok(self.run(Some(n.max(1))))
+            }
+            "back" => {
+                let n = args.next().map_or(Ok(1), |a| parse_num(Some(a)))?;
+                let undone = (0..n.max(1)).take_while(|_| self.prog.step_back()).count();
+                self.resync();
+                if undone == 0 && self.history_depth.is_none() {
+                    return Err("history is off, turn it on with 'history on'".to_string());
+                }
+                if undone == 0 {
+                    return Err("no history to step back through".to_string());
+                }
+                Ok(self.current_instruction())
+            }
+            "history" => match args.next() {
+                Some("on") => {
+                    let depth = args
+                        .next()
+                        .map_or(Ok(HISTORY_DEPTH as i64), |a| parse_num(Some(a)))?;
+                    let depth = usize::try_from(depth).map_err(|_| "invalid depth")?;
+                    self.prog.enable_history(depth);
+                    self.history_depth = Some(depth);
+                    Ok(format!("recording the last {} instructions", depth))
+                }
+                Some("off") => {
+                    self.prog.disable_history();
+                    self.history_depth = None;
+                    Ok("history off".to_string())
+                }
+                _ => Err("expected 'history on [n]' or 'history off'".to_string()),
+            },
+            "c" | "continue" => Ok(self.run(None)),
+            "b" | "break" => {
+                let address = parse_num(args.next())?;
+                self.breakpoints.insert(address);
+                Ok(format!("breakpoint at {}", address))
+            }
+            "delete" => {
+                let address = parse_num(args.next())?;
+                if self.breakpoints.remove(&address) {
+                    Ok(format!("deleted breakpoint at {}", address))
+                } else {
+                    Err(format!("no breakpoint at {}", address))
+                }
+            }
+            "w" | "watch" => {
+                let address = parse_num(args.next())?;
+                let value = self.prog.try_get_mem(address).map_err(|e| e.to_string())?;
+                self.watchpoints.insert(address, value);
+                Ok(format!("watching {} (currently {})", address, value))
+            }
+            "unwatch" => {
+                let address = parse_num(args.next())?;
+                match self.watchpoints.remove(&address) {
+                    Some(_) => Ok(format!("stopped watching {}", address)),
+                    None => Err(format!("no watchpoint at {}", address)),
+                }
+            }
+            "info" => {
+                let mut breakpoints = self.breakpoints.iter().collect::<Vec<_>>();
+                breakpoints.sort();
+                Ok(format!(
+                    "ip={} base={} halted={}\ninput={:?}\noutput={} values\nbreakpoints={:?}\nwatchpoints={:?}",
+                    self.prog.instruction_ptr(),
+                    self.prog.base_ptr(),
+                    self.halted,
+                    self.prog.in_buffer,
+                    self.prog.out_buffer.len(),
+                    breakpoints,
+                    self.watchpoints.keys().collect::<Vec<_>>(),
+                ))
+            }
+            "m" | "mem" => {
+                let start = parse_num(args.next())?;
+                let count = args.next().map_or(Ok(8), |a| parse_num(Some(a)))?;
+                let end = start
+                    .checked_add(count)
+                    .ok_or_else(|| "range goes past the last address".to_string())?;
+                let mut out = String::new();
+                for row in (start..end).step_by(8) {
+                    write!(out, "{:>5}:", row).unwrap();
+                    for address in row..row.saturating_add(8).min(end) {
+                        let value = self.prog.try_get_mem(address).map_err(|e| e.to_string())?;
+                        write!(out, " {}", value).unwrap();
+                    }
+                    out.push('\n');
+                }
+                Ok(out.trim_end().to_string())
+            }
+            "set" => {
+                let address = parse_num(args.next())?;
+                let value = parse_num(args.next())?;
+                self.prog
+                    .try_set_mem(address, value)
+                    .map_err(|e| e.to_string())?;
+                if let Some(watched) = self.watchpoints.get_mut(&address) {
+                    *watched = value;
+                }
+                Ok(format!("{} = {}", address, value))
+            }
+            "ip" => {
+                if let Some(arg) = args.next() {
+                    self.prog.set_instruction_ptr(parse_num(Some(arg))?);
+                    self.halted = false;
+                }
+                Ok(self.current_instruction())
+            }
+            "base" => {
+                if let Some(arg) = args.next() {
+                    self.prog.set_base_ptr(parse_num(Some(arg))?);
+                }
+                Ok(format!("base={}", self.prog.base_ptr()))
+            }
+            "dis" => {
+                let mut address = args
+                    .next()
+                    .map_or(Ok(self.prog.instruction_ptr()), |a| parse_num(Some(a)))?;
+                let count = args.next().map_or(Ok(8), |a| parse_num(Some(a)))?;
+                let mut out = String::new();
+                for _ in 0..count {
+                    match self.prog.decode_at(address) {
+                        Some(ins) => {
+                            writeln!(out, "{:>5}: {}", address, ins).unwrap();
+                            address += ins.size() as i64;
+                        }
+                        None => {
+                            let value =
+                                self.prog.try_get_mem(address).map_err(|e| e.to_string())?;
+                            writeln!(out, "{:>5}: data {}", address, value).unwrap();
+                            address += 1;
+                        }
+                    }
+                }
+                Ok(out.trim_end().to_string())
+            }
+            "in" => {
+                let values = args
+                    .map(|a| parse_num(Some(a)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.prog.in_buffer.extend(values);
+                Ok(format!("{} values queued", self.prog.in_buffer.len()))
+            }
+            "ascii" => {
+                let text = line.trim_start()[command.len()..].trim_start();
+                self.prog
+                    .in_buffer
+                    .extend(text.bytes().chain(Some(b'\n')).map(i64::from));
+                Ok(format!("{} values queued", self.prog.in_buffer.len()))
+            }
+            "out" => {
+                if args.next() == Some("clear") {
+                    self.prog.out_buffer.clear();
+                    return Ok("output cleared".to_string());
+                }
+                let mut out = String::new();
+                for &value in self.prog.out_buffer.iter() {
+                    self.format_output(value, &mut out);
+                }
+                Ok(out)
+            }
+            "display" => match args.next() {
+                Some("ascii") => {
+                    self.display = OutputDisplay::Ascii;
+                    Ok("showing output as ASCII".to_string())
+                }
+                Some("num") => {
+                    self.display = OutputDisplay::Numeric;
+                    Ok("showing output as numbers".to_string())
+                }
+                _ => Err("expected 'display ascii' or 'display num'".to_string()),
+            },
+            "profile" => match args.next() {
+                Some("start") => {
+                    self.prog.start_profile();
+                    Ok("profiling started".to_string())
+                }
+                Some("stop") => match self.prog.stop_profile() {
+                    Some(profile) => Ok(profile.report(&self.prog, 10)),
+                    None => Err("not profiling".to_string()),
+                },
+                arg => {
+                    let n = arg.map_or(Ok(10), |a| parse_num(Some(a)))?;
+                    match self.prog.profile() {
+                        Some(profile) => Ok(profile.report(&self.prog, n.max(0) as usize)),
+                        None => Err("not profiling, try 'profile start'".to_string()),
+                    }
+                }
+            },
+            "save" => {
+                let path = args.next().ok_or("missing file name")?;
+                self.prog
+                    .save_snapshot(path)
+                    .map_err(|e| format!("can't save snapshot: {}", e))?;
+                Ok(format!("saved to {}", path))
+            }
+            "load" => {
+                let path = args.next().ok_or("missing file name")?;
+                self.prog = IntCodeState::load_snapshot(path).map_err(|e| e.to_string())?;
+                if let Some(depth) = self.history_depth {
+                    self.prog.enable_history(depth);
+                }
+                self.resync();
+                Ok(self.current_instruction())
+            }
+            "h" | "help" => Ok(HELP.to_string()),
+            other => Err(format!("unknown command '{}', try 'help'", other)),
+        }
+    }
+}
+
+fn main() {
+    let args = Cli::parse();
+
+    let inp = fs::read_to_string(args.input).expect("can't open input file");
+
+    let mut debugger = Debugger::new(inp.as_str().into());
+    println!("{}", debugger.current_instruction());
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("(icdb) ");
+        io::stdout().flush().expect("can't flush stdout");
+
+        let Some(Ok(line)) = lines.next() else {
+            break;
+        };
+        if matches!(line.trim(), "q" | "quit") {
+            break;
+        }
+        match debugger.execute(&line) {
+            Ok(out) => println!("{}", out),
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debugger(program: Vec<i64>) -> Debugger {
+        Debugger::new(program.into())
+    }
+
+    #[test]
+    fn test_breakpoint_and_continue() {
+        let mut dbg = debugger(vec![1101, 1, 2, 9, 1101, 3, 4, 10, 99, 0, 0]);
+        dbg.execute("break 4").unwrap();
+        assert!(dbg.execute("continue").unwrap().contains("breakpoint 4"));
+        assert_eq!(dbg.prog.get_mem(9), 3);
+        assert!(dbg.execute("continue").unwrap().contains("program halted"));
+        assert_eq!(dbg.prog.get_mem(10), 7);
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut dbg = debugger(vec![1101, 1, 2, 9, 1101, 3, 4, 10, 99, 0, 0]);
+        dbg.execute("watch 10").unwrap();
+        let out = dbg.execute("c").unwrap();
+        assert!(out.contains("watchpoint 10: 0 -> 7"));
+        assert_eq!(dbg.prog.instruction_ptr(), 8);
+    }
+
+    #[test]
+    fn test_input_and_output() {
+        let mut dbg = debugger(vec![3, 0, 4, 0, 99]);
+        assert!(dbg.execute("c").unwrap().contains("waiting for input"));
+        dbg.execute("ascii A").unwrap();
+        assert_eq!(dbg.execute("step").unwrap(), "    2: out 0");
+        assert!(dbg.execute("step").unwrap().starts_with('A'));
+        dbg.execute("display num").unwrap();
+        assert_eq!(dbg.execute("out").unwrap(), "[65]\n");
+    }
+
+    #[test]
+    fn test_non_canonical_input() {
+        // An input instruction with a mode digit for a second operand
+        let mut dbg = debugger(vec![1003, 0, 4, 0, 99]);
+        assert!(dbg.execute("c").unwrap().contains("waiting for input"));
+        dbg.execute("in 7").unwrap();
+        dbg.execute("display num").unwrap();
+        assert!(dbg.execute("c").unwrap().starts_with("[7]"));
+    }
+
+    #[test]
+    fn test_edit_state() {
+        let mut dbg = debugger(vec![204, 0, 99]);
+        dbg.execute("set 5 42").unwrap();
+        dbg.execute("base 5").unwrap();
+        dbg.execute("display num").unwrap();
+        assert!(dbg.execute("step").unwrap().starts_with("[42]"));
+        assert_eq!(dbg.execute("mem 0 3").unwrap(), "    0: 204 0 99");
+        assert!(dbg.execute("mem 9223372036854775800 100").is_err());
+        assert!(dbg.execute("frobnicate").is_err());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("icdb_test_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut dbg = debugger(vec![1101, 1, 2, 9, 1101, 3, 4, 10, 99, 0, 0]);
+        dbg.execute("step").unwrap();
+        dbg.execute(&format!("save {}", path)).unwrap();
+        dbg.execute("c").unwrap();
+        assert_eq!(
+            dbg.execute(&format!("load {}", path)).unwrap(),
+            "    4: add #3, #4, 10"
+        );
+        assert_eq!(dbg.prog.get_mem(10), 0);
+        std::fs::remove_file(path).unwrap();
+        assert!(dbg.execute(&format!("load {}", path)).is_err());
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut dbg = debugger(vec![1101, 1, 2, 9, 1101, 3, 4, 10, 99, 0, 0]);
+        dbg.execute("step").unwrap();
+        assert!(dbg.execute("back").unwrap_err().contains("history on"));
+        assert_eq!(dbg.prog.history_len(), 0);
+        dbg.execute("history on").unwrap();
+        assert!(dbg.execute("back").is_err());
+        assert!(dbg.execute("c").unwrap().contains("program halted"));
+        assert_eq!(dbg.execute("back").unwrap(), "    4: add #3, #4, 10");
+        assert_eq!(dbg.prog.get_mem(10), 0);
+        // Only what ran after history was turned on can be undone
+        assert!(dbg.execute("back 5").is_err());
+        assert_eq!(dbg.prog.get_mem(9), 3);
+        assert!(dbg.execute("c").unwrap().contains("program halted"));
+        dbg.execute("history off").unwrap();
+        assert_eq!(dbg.prog.history_len(), 0);
+    }
+
+    #[test]
+    fn test_profile() {
+        let mut dbg = debugger(vec![1101, 1, 2, 9, 1101, 3, 4, 10, 99, 0, 0]);
+        assert!(dbg.execute("profile").is_err());
+        dbg.execute("profile start").unwrap();
+        dbg.execute("c").unwrap();
+        assert!(dbg.execute("profile 1").unwrap().contains("    0:"));
+        let report = dbg.execute("profile stop").unwrap();
+        assert!(report.starts_with("3 instructions executed"));
+        assert!(dbg.execute("profile stop").is_err());
+    }
+}
@@ -0,0 +1,12 @@
+use advent_of_code_2019::intcode::decompile::decompile;
+use advent_of_code_2019::intcode::parse_intcode_to_vec;
+use advent_of_code_2019::{Cli, Parser};
+use std::fs;
+
+fn main() {
+    let args = Cli::parse();
+
+    let inp = fs::read_to_string(args.input).expect("can't open input file");
+
+    print!("{}", decompile(&parse_intcode_to_vec(&inp)));
+}
@@ -0,0 +1,260 @@
+use advent_of_code_2019::intcode::IntCodeState;
+use clap::Parser;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+// Transcripts have one entry per line, each starting with a marker:
+//
+//   | a line of text output by the program
+//   = a value output by the program which isn't ASCII
+//   > a line typed in
+//
+// Replaying one types in its lines in order, then carries on from stdin.
+
+#[derive(Parser)]
+struct Args {
+    #[clap(short, long)]
+    input: String,
+    /// File to write the session's transcript to
+    #[clap(short, long)]
+    record: Option<String>,
+    /// Transcript whose input to replay before reading from stdin
+    #[clap(long)]
+    replay: Option<String>,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+enum Entry {
+    Output(String),
+    Value(i64),
+    Input(String),
+}
+
+fn format_transcript(entries: &[Entry]) -> String {
+    entries
+        .iter()
+        .map(|entry| match entry {
+            Entry::Output(line) => format!("| {}\n", line),
+            Entry::Value(value) => format!("= {}\n", value),
+            Entry::Input(line) => format!("> {}\n", line),
+        })
+        .collect()
+}
+
+fn parse_transcript(transcript: &str) -> Result<Vec<Entry>, String> {
+    transcript
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            let (marker, rest) = (line.get(..2), line.get(2..).unwrap_or(""));
+            match marker.unwrap_or(line) {
+                "| " | "|" => Ok(Entry::Output(rest.to_string())),
+                "> " | ">" => Ok(Entry::Input(rest.to_string())),
+                "= " => rest
+                    .parse()
+                    .map(Entry::Value)
+                    .map_err(|_| format!("line {}: invalid value '{}'", idx + 1, rest)),
+                _ => Err(format!("line {}: unknown entry '{}'", idx + 1, line)),
+            }
+        })
+        .collect()
+}
+
+// Runs the program, typing in the scripted lines and then those read from
+// `input`, until it halts or the input runs out. Returns the transcript.
+fn play<R: BufRead, W: Write>(
+    prog: &mut IntCodeState,
+    script: &[String],
+    input: R,
+    mut out: W,
+) -> io::Result<Vec<Entry>> {
+    let mut entries = vec![];
+    let mut script = script.iter().cloned();
+    let mut input = input.lines();
+    let mut ascii = prog.ascii();
+    loop {
+        let output = ascii
+            .try_read_until_prompt()
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        write!(out, "{}", output.text)?;
+        entries.extend(
+            output
+                .text
+                .split_inclusive('\n')
+                .map(|line| Entry::Output(line.trim_end_matches('\n').to_string())),
+        );
+        for &value in output.values.iter() {
+            writeln!(out, "[{}]", value)?;
+            entries.push(Entry::Value(value));
+        }
+        if output.halted {
+            break;
+        }
+
+        out.flush()?;
+        let line = match script.next() {
+            Some(line) => {
+                writeln!(out, "{}", line)?;
+                line
+            }
+            None => match input.next() {
+                Some(line) => line?,
+                None => break,
+            },
+        };
+        ascii.send_line(&line);
+        entries.push(Entry::Input(line));
+    }
+    Ok(entries)
+}
+
+// Index of the first output entry which differs between the transcripts
+fn first_divergence(recorded: &[Entry], played: &[Entry]) -> Option<usize> {
+    let outputs = |entries: &[Entry]| {
+        entries
+            .iter()
+            .filter(|e| !matches!(e, Entry::Input(_)))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let (recorded, played) = (outputs(recorded), outputs(played));
+    (0..recorded.len().max(played.len())).find(|&idx| recorded.get(idx) != played.get(idx))
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let inp = fs::read_to_string(args.input).expect("can't open input file");
+    let mut prog: IntCodeState = inp.as_str().into();
+
+    let recorded = args.replay.map(|path| {
+        let transcript = fs::read_to_string(path).expect("can't open transcript");
+        parse_transcript(&transcript).unwrap_or_else(|e| panic!("{}", e))
+    });
+    let script = recorded
+        .iter()
+        .flatten()
+        .filter_map(|entry| match entry {
+            Entry::Input(line) => Some(line.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let stdin = io::stdin();
+    let entries = match play(&mut prog, &script, stdin.lock(), io::stdout()) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return;
+        }
+    };
+
+    if let Some(recorded) = recorded {
+        let replayed = entries.len().min(
+            recorded
+                .iter()
+                .rposition(|e| matches!(e, Entry::Input(_)))
+                .map_or(0, |idx| idx + 1),
+        );
+        if let Some(idx) = first_divergence(&recorded[..replayed], &entries[..replayed]) {
+            eprintln!(
+                "output differs from the transcript at output line {}",
+                idx + 1
+            );
+        }
+    }
+    if let Some(path) = args.record {
+        fs::write(path, format_transcript(&entries)).expect("can't write transcript");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use advent_of_code_2019::intcode::asm::assemble;
+
+    // Asks for a name and greets it, until given an empty one. Outputs
+    // how many it greeted when it halts.
+    fn greeter() -> IntCodeState {
+        let text = |s: &str| {
+            s.bytes()
+                .map(|b| format!("out #{}", b))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        assemble(&format!(
+            "
+            ask:    {}
+            read:   in c
+                    eq c, #10, t
+                    jt t, #eol
+                    add n, #1, n
+                    jf greet, #hello
+                    out c
+                    jt #1, #read
+            hello:  {}
+                    out c
+                    add #1, #0, greet
+                    jt #1, #read
+            eol:    jf n, #done
+                    out #10
+                    add #0, #0, n
+                    add #0, #0, greet
+                    add count, #1, count
+                    jt #1, #ask
+            done:   out count
+                    hlt
+            c:      data 0
+            t:      data 0
+            n:      data 0
+            greet:  data 0
+            count:  data 1000
+            ",
+            text("Name?\n"),
+            text("Hi ")
+        ))
+        .unwrap()
+        .into()
+    }
+
+    #[test]
+    fn test_play_and_replay() {
+        let mut screen = vec![];
+        let entries = play(&mut greeter(), &[], "Bob\n\n".as_bytes(), &mut screen).unwrap();
+        assert_eq!(
+            String::from_utf8(screen).unwrap(),
+            "Name?\nHi Bob\nName?\n[1001]\n"
+        );
+        let transcript = format_transcript(&entries);
+        assert_eq!(
+            transcript,
+            "| Name?\n> Bob\n| Hi Bob\n| Name?\n> \n= 1001\n"
+        );
+
+        let recorded = parse_transcript(&transcript).unwrap();
+        assert_eq!(recorded, entries);
+        let mut screen = vec![];
+        let replayed = play(
+            &mut greeter(),
+            &["Bob".to_string()],
+            "Al\n".as_bytes(),
+            &mut screen,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(screen).unwrap(),
+            "Name?\nBob\nHi Bob\nName?\nHi Al\nName?\n"
+        );
+        assert_eq!(first_divergence(&recorded[..4], &replayed[..4]), None);
+        assert_eq!(first_divergence(&recorded, &replayed), Some(3));
+    }
+
+    #[test]
+    fn test_bad_transcript() {
+        assert_eq!(
+            parse_transcript("| ok\n= x\n"),
+            Err("line 2: invalid value 'x'".to_string())
+        );
+        assert!(parse_transcript("hello\n").is_err());
+    }
+}
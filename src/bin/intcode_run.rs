@@ -0,0 +1,65 @@
+use advent_of_code_2019::intcode::word::{parse_words, Checked, Word};
+use advent_of_code_2019::intcode::{IntCodeEvent, IntCodeState};
+use clap::{Parser, ValueEnum};
+use num::BigInt;
+use std::fmt;
+use std::fs;
+
+#[derive(Clone, Copy, ValueEnum)]
+enum WordType {
+    /// Fastest, but only wraps on overflow in release builds
+    I64,
+    I128,
+    /// i64 which reports overflow rather than wrapping
+    Checked,
+    Checked128,
+    Big,
+}
+
+#[derive(Parser)]
+struct Args {
+    #[clap(short, long)]
+    input: String,
+    #[clap(short, long, value_enum, default_value = "checked")]
+    word: WordType,
+    /// Comma-separated values to give the program as input
+    #[clap(long, default_value = "")]
+    values: String,
+}
+
+// Runs the program until it halts, printing each output as it goes
+fn run<W: Word>(program: &str, values: &str) -> Result<(), String>
+where
+    W::Err: fmt::Display,
+{
+    let program = parse_words::<W>(program).map_err(|e| format!("bad program: {}", e))?;
+    let mut vm = IntCodeState::from(program);
+    if !values.trim().is_empty() {
+        let values = parse_words::<W>(values).map_err(|e| format!("bad input: {}", e))?;
+        vm.in_buffer.extend(values);
+    }
+    loop {
+        match vm.try_run_until_event().map_err(|e| e.to_string())? {
+            IntCodeEvent::Output(value) => println!("{}", value),
+            IntCodeEvent::NeedsInput => return Err("ran out of input".to_string()),
+            IntCodeEvent::Halted => return Ok(()),
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let inp = fs::read_to_string(args.input).expect("can't open input file");
+    let result = match args.word {
+        WordType::I64 => run::<i64>(&inp, &args.values),
+        WordType::I128 => run::<i128>(&inp, &args.values),
+        WordType::Checked => run::<Checked<i64>>(&inp, &args.values),
+        WordType::Checked128 => run::<Checked<i128>>(&inp, &args.values),
+        WordType::Big => run::<BigInt>(&inp, &args.values),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
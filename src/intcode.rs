@@ -1,42 +1,93 @@
-use ahash::AHashMap;
 use std::collections::VecDeque;
-
-const PANIC_ON_HIGH_MEM: bool = true;
-
+use std::error::Error;
+use std::fmt;
+
+pub mod ascii;
+pub mod asm;
+pub mod async_vm;
+pub mod cfg;
+pub mod custom;
+pub mod decompile;
+pub mod disasm;
+mod dispatch;
+#[cfg(test)]
+pub mod fuzz;
+mod history;
+mod instrument;
+pub mod io;
+mod limits;
+mod memory;
+pub mod network;
+pub mod profile;
+pub mod snapshot;
+pub mod symbolic;
+pub mod trace;
+pub mod watch;
+pub mod word;
+
+use custom::CustomOpcodes;
+use dispatch::HandlerCache;
+use instrument::Instruments;
+pub use io::IntcodeIo;
+pub use limits::{Limits, RunOutcome};
+use memory::Memory;
+pub use memory::MemoryModel;
+use word::Word;
+
+// Generic over the word it computes with, see word::Word. Only i64 gets
+// pre-decoding, instrumentation, custom opcodes and the tools built on
+// them, so other words run several times slower.
 #[derive(Eq, PartialEq, Debug, Clone)]
-pub struct IntCodeState<const LOW_MEM_AMOUNT: usize = 256> {
+pub struct IntCodeState<W = i64> {
     instruction_ptr: i64,
     base_ptr: i64,
-    low_memory: Vec<i64>,
-    high_memory: AHashMap<i64, i64>,
-    pub out_buffer: VecDeque<i64>,
+    memory: Memory<W>,
+    handlers: HandlerCache,
+    pub in_buffer: VecDeque<W>,
+    pub out_buffer: VecDeque<W>,
+    instruments: Instruments,
+    custom_opcodes: CustomOpcodes,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum IntCodeEvent<W = i64> {
+    NeedsInput,
+    Output(W),
+    Halted,
 }
 
-impl<const LOW_MEM_AMOUNT: usize> From<&[i64]> for IntCodeState<LOW_MEM_AMOUNT> {
-    fn from(item: &[i64]) -> Self {
-        let mut low_mem = item.to_vec();
-        low_mem.extend(vec![0; LOW_MEM_AMOUNT.saturating_sub(low_mem.len())]);
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+enum Step {
+    Continue,
+    Blocked,
+    Output,
+    Halted,
+}
+
+impl<W: Word> IntCodeState<W> {
+    pub fn new(program: Vec<W>, memory_model: MemoryModel) -> Self {
         IntCodeState {
             instruction_ptr: 0,
             base_ptr: 0,
-            low_memory: low_mem,
-            high_memory: AHashMap::default(),
+            handlers: HandlerCache::new(program.len()),
+            memory: Memory::new(program, memory_model),
+            in_buffer: VecDeque::new(),
             out_buffer: VecDeque::new(),
+            instruments: Instruments::default(),
+            custom_opcodes: CustomOpcodes::default(),
         }
     }
 }
 
-impl<const LOW_MEM_AMOUNT: usize> From<Vec<i64>> for IntCodeState<LOW_MEM_AMOUNT> {
-    fn from(item: Vec<i64>) -> Self {
-        let mut low_mem = item;
-        low_mem.extend(vec![0; LOW_MEM_AMOUNT.saturating_sub(low_mem.len())]);
-        IntCodeState {
-            instruction_ptr: 0,
-            base_ptr: 0,
-            low_memory: low_mem,
-            high_memory: AHashMap::default(),
-            out_buffer: VecDeque::new(),
-        }
+impl<W: Word> From<&[W]> for IntCodeState<W> {
+    fn from(item: &[W]) -> Self {
+        IntCodeState::new(item.to_vec(), MemoryModel::default())
+    }
+}
+
+impl<W: Word> From<Vec<W>> for IntCodeState<W> {
+    fn from(item: Vec<W>) -> Self {
+        IntCodeState::new(item, MemoryModel::default())
     }
 }
 
@@ -47,23 +98,72 @@ pub fn parse_intcode_to_vec(inp: &str) -> Vec<i64> {
         .collect::<Vec<i64>>()
 }
 
-impl<const LOW_MEM_AMOUNT: usize> From<&str> for IntCodeState<LOW_MEM_AMOUNT> {
+impl From<&str> for IntCodeState {
     fn from(item: &str) -> Self {
         parse_intcode_to_vec(item).into()
     }
 }
 
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum IntCodeErrorKind {
+    InvalidOpcode(i64),
+    InvalidMode(u32),
+    ImmediateModeWrite,
+    NegativeAddress,
+    OutOfBounds,
+    // The result of an instruction didn't fit in the word type
+    Overflow,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct IntCodeError {
+    pub kind: IntCodeErrorKind,
+    pub instruction_ptr: i64,
+    // None if the instruction itself couldn't be fetched
+    pub instruction: Option<i64>,
+    pub address: Option<i64>,
+}
+
+impl fmt::Display for IntCodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            IntCodeErrorKind::InvalidOpcode(op) => write!(f, "bad instruction {}", op)?,
+            IntCodeErrorKind::InvalidMode(mode) => write!(f, "unexpected mode {}", mode)?,
+            IntCodeErrorKind::ImmediateModeWrite => {
+                write!(f, "can't set parameter in immediate mode")?
+            }
+            IntCodeErrorKind::NegativeAddress => write!(f, "negative address")?,
+            IntCodeErrorKind::OutOfBounds => write!(f, "out of bounds access")?,
+            IntCodeErrorKind::Overflow => write!(f, "arithmetic overflow")?,
+        }
+        if let Some(address) = self.address {
+            write!(f, " at address {}", address)?;
+        }
+        write!(f, " (ip={}", self.instruction_ptr)?;
+        if let Some(instruction) = self.instruction {
+            write!(f, ", instruction={}", instruction)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl Error for IntCodeError {}
+
 #[derive(Debug)]
 struct Instruction {
     num: u32,
 }
 
-impl From<i64> for Instruction {
+impl TryFrom<i64> for Instruction {
+    type Error = IntCodeErrorKind;
+
     #[inline]
-    fn from(item: i64) -> Self {
-        Instruction {
-            num: item.try_into().expect("invalid instruction"),
-        }
+    fn try_from(item: i64) -> Result<Self, Self::Error> {
+        Ok(Instruction {
+            num: item
+                .try_into()
+                .map_err(|_| IntCodeErrorKind::InvalidOpcode(item))?,
+        })
     }
 }
 
@@ -87,174 +187,374 @@ impl Instruction {
     fn mode3(&self) -> u32 {
         (self.num / 10000) % 10
     }
+
+    // Mode of any operand, for instructions with more than three
+    fn mode(&self, idx: usize) -> u32 {
+        10u32
+            .checked_pow(idx as u32 + 2)
+            .map_or(0, |place| (self.num / place) % 10)
+    }
 }
 
-impl<const LOW_MEM_AMOUNT: usize> IntCodeState<LOW_MEM_AMOUNT> {
+impl<W: Word> IntCodeState<W> {
+    pub fn instruction_ptr(&self) -> i64 {
+        self.instruction_ptr
+    }
+
+    pub fn set_instruction_ptr(&mut self, instruction_ptr: i64) {
+        self.instruction_ptr = instruction_ptr;
+    }
+
+    pub fn base_ptr(&self) -> i64 {
+        self.base_ptr
+    }
+
+    pub fn set_base_ptr(&mut self, base_ptr: i64) {
+        self.base_ptr = base_ptr;
+    }
+
     #[cold]
-    fn get_high_mem(&self, address_absolute: i64) -> i64 {
-        if PANIC_ON_HIGH_MEM {
-            panic!("high mem read address {}", address_absolute);
+    fn fault(&self, kind: IntCodeErrorKind, address: Option<i64>) -> IntCodeError {
+        IntCodeError {
+            kind,
+            instruction_ptr: self.instruction_ptr,
+            instruction: None,
+            address,
         }
-        *self.high_memory.get(&address_absolute).unwrap_or(&0)
+    }
+
+    // A copy to run independently of this one, e.g. one branch of a search.
+    // With MemoryModel::Paged the copies share memory until they write to it.
+    pub fn fork(&self) -> IntCodeState<W> {
+        self.clone()
+    }
+
+    pub fn memory_model(&self) -> MemoryModel {
+        self.memory.model()
+    }
+
+    // One past the highest address the program has accessed so far,
+    // which is at least the length of the program.
+    pub fn memory_high_water_mark(&self) -> usize {
+        self.memory.high_water_mark()
     }
 
     #[inline]
-    pub fn get_mem(&self, address_absolute: i64) -> i64 {
-        debug_assert!(address_absolute >= 0, "Address can't be negative");
-        if (address_absolute as usize) < self.low_memory.len() {
-            self.low_memory[address_absolute as usize]
-        } else {
-            self.get_high_mem(address_absolute)
-        }
+    fn check_address(&self, address_absolute: i64) -> Result<(), IntCodeError> {
+        self.memory
+            .check(address_absolute)
+            .map_err(|kind| self.fault(kind, Some(address_absolute)))
     }
 
-    #[cold]
-    fn set_high_mem(&mut self, address_absolute: i64, new: i64) {
-        if PANIC_ON_HIGH_MEM {
-            panic!("high mem write address {}", address_absolute);
+    #[inline]
+    fn load(&mut self, address_absolute: i64) -> Result<W, IntCodeError> {
+        match self.memory.load(address_absolute) {
+            Ok(value) => Ok(value),
+            Err(kind) => Err(self.fault(kind, Some(address_absolute))),
         }
-        self.high_memory.insert(address_absolute, new);
     }
 
+    // Caller must have validated the address with check_address
     #[inline]
-    pub fn set_mem(&mut self, address_absolute: i64, new: i64) {
-        debug_assert!(address_absolute >= 0, "Address can't be negative");
-        if (address_absolute as usize) < self.low_memory.len() {
-            self.low_memory[address_absolute as usize] = new;
-        } else {
-            self.set_high_mem(address_absolute, new);
-        }
+    fn store(&mut self, address_absolute: i64, new: W) {
+        self.memory.store(address_absolute, new);
+        self.handlers.invalidate(address_absolute);
+    }
+
+    pub fn try_get_mem(&self, address_absolute: i64) -> Result<W, IntCodeError> {
+        self.memory
+            .get(address_absolute)
+            .map_err(|kind| self.fault(kind, Some(address_absolute)))
     }
 
+    pub fn get_mem(&self, address_absolute: i64) -> W {
+        self.try_get_mem(address_absolute)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_set_mem(&mut self, address_absolute: i64, new: W) -> Result<(), IntCodeError> {
+        self.check_address(address_absolute)?;
+        self.store(address_absolute, new);
+        Ok(())
+    }
+
+    pub fn set_mem(&mut self, address_absolute: i64, new: W) {
+        self.try_set_mem(address_absolute, new)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // A word used as an address or jump target
+    fn to_address(&self, word: &W) -> Result<i64, IntCodeError> {
+        word.to_i64().ok_or_else(|| {
+            if *word < W::from_i64(0) {
+                self.fault(IntCodeErrorKind::NegativeAddress, None)
+            } else {
+                self.fault(IntCodeErrorKind::OutOfBounds, None)
+            }
+        })
+    }
+
+    // An address relative to the instruction or the base, which like any
+    // address arithmetic fails rather than wrapping
     #[inline]
-    fn get_parameter(&self, mode: u32, offset: i64) -> i64 {
-        let pos = self.get_mem(self.instruction_ptr + offset);
+    fn offset(&self, base: i64, offset: i64) -> Result<i64, IntCodeError> {
+        base.checked_add(offset)
+            .ok_or_else(|| self.fault(IntCodeErrorKind::Overflow, None))
+    }
+
+    #[inline]
+    fn get_parameter(&mut self, mode: u32, offset: i64) -> Result<W, IntCodeError> {
+        let pos = self.load(self.offset(self.instruction_ptr, offset)?)?;
         if mode == 0 {
-            self.get_mem(pos)
+            self.load(self.to_address(&pos)?)
         } else if mode == 1 {
-            pos
+            Ok(pos)
         } else if mode == 2 {
-            self.get_mem(self.base_ptr + pos)
+            self.load(self.offset(self.base_ptr, self.to_address(&pos)?)?)
         } else {
-            panic!("unexpected mode {:?}", mode)
+            Err(self.fault(IntCodeErrorKind::InvalidMode(mode), None))
         }
     }
 
+    // Resolves and validates the address a parameter writes to, so that
+    // instructions can fail before they have modified any state.
     #[inline]
-    fn set_parameter(&mut self, mode: u32, offset: i64, value: i64) {
-        let pos = self.get_mem(self.instruction_ptr + offset);
-        if mode == 0 {
-            self.set_mem(pos, value)
+    fn parameter_address(&mut self, mode: u32, offset: i64) -> Result<i64, IntCodeError> {
+        let pos = self.load(self.offset(self.instruction_ptr, offset)?)?;
+        let address = if mode == 0 {
+            self.to_address(&pos)?
         } else if mode == 1 {
-            panic!("can't set parameter in immediate mode!")
+            return Err(self.fault(IntCodeErrorKind::ImmediateModeWrite, None));
         } else if mode == 2 {
-            self.set_mem(self.base_ptr + pos, value)
+            self.offset(self.base_ptr, self.to_address(&pos)?)?
         } else {
-            panic!("unexpected mode {:?}", mode)
-        }
-    }
-
-    fn handle_add(&mut self, ins: &Instruction) {
-        let src1 = self.get_parameter(ins.mode1(), 1);
-        let src2 = self.get_parameter(ins.mode2(), 2);
-
-        self.set_parameter(ins.mode3(), 3, src1 + src2);
-
-        self.instruction_ptr += 4;
+            return Err(self.fault(IntCodeErrorKind::InvalidMode(mode), None));
+        };
+        self.check_address(address)?;
+        Ok(address)
     }
 
-    fn handle_mul(&mut self, ins: &Instruction) {
-        let src1 = self.get_parameter(ins.mode1(), 1);
-        let src2 = self.get_parameter(ins.mode2(), 2);
+    fn handle_arithmetic(
+        &mut self,
+        ins: &Instruction,
+        op: fn(&W, &W) -> Option<W>,
+    ) -> Result<(), IntCodeError> {
+        let src1 = self.get_parameter(ins.mode1(), 1)?;
+        let src2 = self.get_parameter(ins.mode2(), 2)?;
+        let dest = self.parameter_address(ins.mode3(), 3)?;
 
-        self.set_parameter(ins.mode3(), 3, src1 * src2);
+        let result =
+            op(&src1, &src2).ok_or_else(|| self.fault(IntCodeErrorKind::Overflow, None))?;
+        self.store(dest, result);
 
         self.instruction_ptr += 4;
+        Ok(())
     }
 
-    fn handle_inp<F>(&mut self, ins: &Instruction, mut input_handler: F)
+    fn handle_inp<F>(
+        &mut self,
+        ins: &Instruction,
+        mut input_handler: F,
+    ) -> Result<Step, IntCodeError>
     where
-        F: FnMut(&mut IntCodeState<LOW_MEM_AMOUNT>) -> Option<i64>,
+        F: FnMut(&mut IntCodeState<W>) -> Option<W>,
     {
+        let dest = self.parameter_address(ins.mode1(), 1)?;
         if let Some(inp) = input_handler(self) {
-            self.set_parameter(ins.mode1(), 1, inp);
+            self.store(dest, inp);
             self.instruction_ptr += 2;
+            Ok(Step::Continue)
+        } else {
+            Ok(Step::Blocked)
         }
     }
 
-    fn handle_out(&mut self, ins: &Instruction) {
-        let x = self.get_parameter(ins.mode1(), 1);
+    fn handle_out(&mut self, ins: &Instruction) -> Result<Step, IntCodeError> {
+        let x = self.get_parameter(ins.mode1(), 1)?;
         self.out_buffer.push_back(x);
         self.instruction_ptr += 2;
+        Ok(Step::Output)
     }
 
-    fn handle_jump_if<const COND: bool>(&mut self, ins: &Instruction) {
-        if (self.get_parameter(ins.mode1(), 1) != 0) == COND {
-            self.instruction_ptr = self.get_parameter(ins.mode2(), 2);
+    fn handle_jump_if<const COND: bool>(&mut self, ins: &Instruction) -> Result<(), IntCodeError> {
+        if (self.get_parameter(ins.mode1(), 1)? != W::from_i64(0)) == COND {
+            let target = self.get_parameter(ins.mode2(), 2)?;
+            self.instruction_ptr = self.to_address(&target)?;
         } else {
             self.instruction_ptr += 3;
         }
+        Ok(())
     }
 
-    fn handle_cmp_eq(&mut self, ins: &Instruction) {
-        let x = self.get_parameter(ins.mode1(), 1);
-        let y = self.get_parameter(ins.mode2(), 2);
-        self.set_parameter(ins.mode3(), 3, if x == y { 1 } else { 0 });
+    fn handle_cmp_eq(&mut self, ins: &Instruction) -> Result<(), IntCodeError> {
+        let x = self.get_parameter(ins.mode1(), 1)?;
+        let y = self.get_parameter(ins.mode2(), 2)?;
+        let dest = self.parameter_address(ins.mode3(), 3)?;
+        self.store(dest, W::from_i64(if x == y { 1 } else { 0 }));
         self.instruction_ptr += 4;
+        Ok(())
     }
 
     // Note: separate cmp_lt and cmp_eq implementations to help branch predictor
     // 20% perf improvement
-    fn handle_cmp_lt(&mut self, ins: &Instruction) {
-        let x = self.get_parameter(ins.mode1(), 1);
-        let y = self.get_parameter(ins.mode2(), 2);
-        self.set_parameter(ins.mode3(), 3, if x < y { 1 } else { 0 });
+    fn handle_cmp_lt(&mut self, ins: &Instruction) -> Result<(), IntCodeError> {
+        let x = self.get_parameter(ins.mode1(), 1)?;
+        let y = self.get_parameter(ins.mode2(), 2)?;
+        let dest = self.parameter_address(ins.mode3(), 3)?;
+        self.store(dest, W::from_i64(if x < y { 1 } else { 0 }));
         self.instruction_ptr += 4;
+        Ok(())
     }
 
-    fn handle_adjust_base_ptr(&mut self, ins: &Instruction) {
-        self.base_ptr += self.get_parameter(ins.mode1(), 1);
+    fn handle_adjust_base_ptr(&mut self, ins: &Instruction) -> Result<(), IntCodeError> {
+        let adjustment = self.get_parameter(ins.mode1(), 1)?;
+        self.base_ptr = adjustment
+            .to_i64()
+            .and_then(|adjustment| self.base_ptr.checked_add(adjustment))
+            .ok_or_else(|| self.fault(IntCodeErrorKind::Overflow, None))?;
         self.instruction_ptr += 2;
+        Ok(())
+    }
+
+    #[inline]
+    fn step<F>(&mut self, input_handler: F) -> Result<Step, IntCodeError>
+    where
+        F: FnMut(&mut IntCodeState<W>) -> Option<W>,
+    {
+        W::step(self, input_handler)
+    }
+
+    #[cold]
+    fn with_instruction(&self, e: IntCodeError) -> IntCodeError {
+        IntCodeError {
+            instruction: e.instruction.or_else(|| {
+                self.try_get_mem(self.instruction_ptr)
+                    .ok()
+                    .and_then(|raw| raw.to_i64())
+            }),
+            ..e
+        }
     }
 
-    pub fn execute_single_step<F>(&mut self, mut input_handler: F) -> bool
+    // Decodes the instruction from scratch. For i64 this is only used for
+    // instructions which can't be pre-decoded, so errors are reported
+    // exactly as they always have been.
+    fn step_generic<F>(&mut self, mut input_handler: F) -> Result<Step, IntCodeError>
     where
-        F: FnMut(&mut IntCodeState<LOW_MEM_AMOUNT>) -> Option<i64>,
+        F: FnMut(&mut IntCodeState<W>) -> Option<W>,
     {
-        let raw_instruction: i64 = self.get_mem(self.instruction_ptr);
-        let instruction: Instruction = raw_instruction.into();
+        let raw = self.load(self.instruction_ptr)?;
+        let raw_instruction = raw.to_i64();
+        let with_instruction = |e: IntCodeError| IntCodeError {
+            instruction: raw_instruction,
+            ..e
+        };
+        let instruction: Instruction = raw_instruction
+            .ok_or(IntCodeErrorKind::InvalidOpcode(if raw < W::from_i64(0) {
+                i64::MIN
+            } else {
+                i64::MAX
+            }))
+            .and_then(Instruction::try_from)
+            .map_err(|kind| with_instruction(self.fault(kind, None)))?;
         match instruction.typ() {
-            1 => self.handle_add(&instruction),
-            2 => self.handle_mul(&instruction),
+            1 => self
+                .handle_arithmetic(&instruction, W::checked_add)
+                .map(|_| Step::Continue),
+            2 => self
+                .handle_arithmetic(&instruction, W::checked_mul)
+                .map(|_| Step::Continue),
             3 => self.handle_inp(&instruction, &mut input_handler),
             4 => self.handle_out(&instruction),
-            5 => self.handle_jump_if::<true>(&instruction),
-            6 => self.handle_jump_if::<false>(&instruction),
-            7 => self.handle_cmp_lt(&instruction),
-            8 => self.handle_cmp_eq(&instruction),
-            9 => self.handle_adjust_base_ptr(&instruction),
-            99 => return true,
-            other => panic!("bad instruction {:?}", other),
+            5 => self
+                .handle_jump_if::<true>(&instruction)
+                .map(|_| Step::Continue),
+            6 => self
+                .handle_jump_if::<false>(&instruction)
+                .map(|_| Step::Continue),
+            7 => self.handle_cmp_lt(&instruction).map(|_| Step::Continue),
+            8 => self.handle_cmp_eq(&instruction).map(|_| Step::Continue),
+            9 => self
+                .handle_adjust_base_ptr(&instruction)
+                .map(|_| Step::Continue),
+            99 => Ok(Step::Halted),
+            _ => W::unknown_opcode(self, &instruction),
         }
+        .map_err(with_instruction)
+    }
 
-        false
+    // On error the state is left exactly as it was before the failing
+    // instruction, so it can be inspected afterwards.
+    pub fn try_execute_single_step<F>(&mut self, input_handler: F) -> Result<bool, IntCodeError>
+    where
+        F: FnMut(&mut IntCodeState<W>) -> Option<W>,
+    {
+        Ok(self.step(input_handler)? == Step::Halted)
+    }
+
+    pub fn execute_single_step<F>(&mut self, input_handler: F) -> bool
+    where
+        F: FnMut(&mut IntCodeState<W>) -> Option<W>,
+    {
+        self.try_execute_single_step(input_handler)
+            .unwrap_or_else(|e| panic!("{}", e))
     }
 
-    pub fn execute_until_halt<F>(&mut self, mut input_handler: F)
+    pub fn try_execute_until_halt<F>(&mut self, mut input_handler: F) -> Result<(), IntCodeError>
     where
-        F: FnMut(&mut IntCodeState<LOW_MEM_AMOUNT>) -> Option<i64>,
+        F: FnMut(&mut IntCodeState<W>) -> Option<W>,
     {
         loop {
-            let halt = self.execute_single_step(&mut input_handler);
+            let halt = self.try_execute_single_step(&mut input_handler)?;
             if halt {
-                break;
+                return Ok(());
             }
         }
     }
 
+    pub fn execute_until_halt<F>(&mut self, input_handler: F)
+    where
+        F: FnMut(&mut IntCodeState<W>) -> Option<W>,
+    {
+        self.try_execute_until_halt(input_handler)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
     pub fn execute_until_halt_no_input(&mut self) {
         self.execute_until_halt(|_| panic!("should not ask for input"));
     }
+
+    // Runs until the program needs input which isn't in in_buffer, produces
+    // an output or halts. Outputs are returned rather than left in out_buffer.
+    pub fn try_run_until_event(&mut self) -> Result<IntCodeEvent<W>, IntCodeError> {
+        loop {
+            match self.step(|s| s.in_buffer.pop_front())? {
+                Step::Continue => {}
+                Step::Blocked => return Ok(IntCodeEvent::NeedsInput),
+                Step::Output => {
+                    let out = self.out_buffer.pop_back().expect("output should exist");
+                    return Ok(IntCodeEvent::Output(out));
+                }
+                Step::Halted => return Ok(IntCodeEvent::Halted),
+            }
+        }
+    }
+
+    pub fn run_until_event(&mut self) -> IntCodeEvent<W> {
+        self.try_run_until_event()
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+impl IntCodeState {
+    #[inline]
+    fn step_plain<F>(&mut self, mut input_handler: F) -> Result<Step, IntCodeError>
+    where
+        F: FnMut(&mut IntCodeState) -> Option<i64>,
+    {
+        self.step_cached(&mut input_handler)
+            .map_err(|e| self.with_instruction(e))
+    }
 }
 
 #[cfg(test)]
@@ -381,4 +681,153 @@ mod tests {
         prog.execute_until_halt_no_input();
         assert_eq!(prog.out_buffer.pop_front(), Some(1125899906842624));
     }
+
+    #[test]
+    fn test_bad_opcode_returns_error() {
+        let mut prog: IntCodeState = vec![1101, 1, 2, 5, 42, 0].into();
+        let err = prog.try_execute_until_halt(|_| None).unwrap_err();
+        assert_eq!(err.kind, IntCodeErrorKind::InvalidOpcode(42));
+        assert_eq!(err.instruction_ptr, 4);
+        assert_eq!(err.instruction, Some(42));
+        assert_eq!(prog.instruction_ptr(), 4);
+        assert_eq!(prog.get_mem(5), 3);
+    }
+
+    #[test]
+    fn test_immediate_mode_write_leaves_state_intact() {
+        let original: IntCodeState = vec![11101, 1, 2, 3, 99].into();
+        let mut prog = original.clone();
+        let err = prog.try_execute_single_step(|_| None).unwrap_err();
+        assert_eq!(err.kind, IntCodeErrorKind::ImmediateModeWrite);
+        assert_eq!(err.instruction, Some(11101));
+        assert_eq!(prog, original);
+    }
+
+    #[test]
+    fn test_bad_mode_returns_error() {
+        let mut prog: IntCodeState = vec![304, 0, 99].into();
+        let err = prog.try_execute_single_step(|_| None).unwrap_err();
+        assert_eq!(err.kind, IntCodeErrorKind::InvalidMode(3));
+        assert_eq!(err.address, None);
+    }
+
+    #[test]
+    fn test_negative_address_returns_error() {
+        let mut prog: IntCodeState = vec![109, -10, 204, 3, 99].into();
+        let err = prog.try_execute_until_halt(|_| None).unwrap_err();
+        assert_eq!(err.kind, IntCodeErrorKind::NegativeAddress);
+        assert_eq!(err.address, Some(-7));
+        assert_eq!(err.instruction_ptr, 2);
+        assert_eq!(prog.base_ptr(), -10);
+    }
+
+    #[test]
+    fn test_out_of_bounds_returns_error_without_consuming_input() {
+        let mut prog: IntCodeState = IntCodeState::new(vec![3, 1000, 99], MemoryModel::Strict(4));
+        let mut inputs = vec![5];
+        let err = prog.try_execute_single_step(|_| inputs.pop()).unwrap_err();
+        assert_eq!(err.kind, IntCodeErrorKind::OutOfBounds);
+        assert_eq!(err.address, Some(1000));
+        assert_eq!(inputs, vec![5]);
+    }
+
+    #[test]
+    fn test_memory_models_agree() {
+        // Writes to address 1000, then reads it back
+        let program: Vec<i64> = vec![1101, 12, 30, 1000, 4, 1000, 99];
+        for model in [
+            MemoryModel::Strict(1001),
+            MemoryModel::Dense,
+            MemoryModel::Sparse,
+            MemoryModel::Paged,
+        ] {
+            let mut prog = IntCodeState::new(program.clone(), model);
+            prog.execute_until_halt_no_input();
+            assert_eq!(prog.out_buffer.pop_front(), Some(42));
+            assert_eq!(prog.memory_high_water_mark(), 1001);
+        }
+    }
+
+    #[test]
+    fn test_high_water_mark_counts_reads() {
+        let mut prog: IntCodeState = vec![4, 500, 99].into();
+        assert_eq!(prog.memory_high_water_mark(), 3);
+        prog.execute_until_halt_no_input();
+        assert_eq!(prog.out_buffer.pop_front(), Some(0));
+        assert_eq!(prog.memory_high_water_mark(), 501);
+    }
+
+    #[test]
+    fn test_state_is_send_and_sync() {
+        fn check<T: Send + Sync>() {}
+        check::<IntCodeState>();
+    }
+
+    #[test]
+    fn test_fork_search() {
+        // Reads moves until it has seen 1, 2, 3 in a row, then outputs how
+        // many moves that took. Outputs 0 after every other move.
+        let program = asm::assemble(
+            "
+            loop:   in move
+                    add count, #1, count
+                    add progress, #1, expected
+                    eq move, expected, ok
+                    jf ok, #reset
+                    add progress, #1, progress
+                    eq progress, #3, ok
+                    jt ok, #done
+                    out #0
+                    jt #1, #loop
+            reset:  eq move, #1, progress
+                    out #0
+                    jt #1, #loop
+            done:   out count
+                    hlt
+            move:     data 0
+            count:    data 0
+            progress: data 0
+            expected: data 0
+            ok:       data 0
+            ",
+        )
+        .unwrap();
+        let mut queue = VecDeque::from([IntCodeState::new(program, MemoryModel::Paged)]);
+        let found = loop {
+            let prog = queue.pop_front().unwrap();
+            let mut done = None;
+            for mv in 1..=3 {
+                let mut child = prog.fork();
+                child.in_buffer.push_back(mv);
+                match child.run_until_event() {
+                    IntCodeEvent::Output(0) => queue.push_back(child),
+                    IntCodeEvent::Output(n) => done = Some(n),
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+            if let Some(n) = done {
+                break n;
+            }
+        };
+        assert_eq!(found, 3);
+    }
+
+    #[test]
+    fn test_run_until_event() {
+        let mut prog: IntCodeState = vec![3, 11, 1001, 11, 1, 11, 4, 11, 1105, 1, 0, 0].into();
+        assert_eq!(prog.run_until_event(), IntCodeEvent::NeedsInput);
+        assert_eq!(prog.run_until_event(), IntCodeEvent::NeedsInput);
+        prog.in_buffer.push_back(41);
+        assert_eq!(prog.run_until_event(), IntCodeEvent::Output(42));
+        assert!(prog.out_buffer.is_empty());
+        assert_eq!(prog.run_until_event(), IntCodeEvent::NeedsInput);
+    }
+
+    #[test]
+    fn test_run_until_event_halts() {
+        let mut prog: IntCodeState = vec![104, 7, 99].into();
+        assert_eq!(prog.run_until_event(), IntCodeEvent::Output(7));
+        assert_eq!(prog.run_until_event(), IntCodeEvent::Halted);
+        assert_eq!(prog.run_until_event(), IntCodeEvent::Halted);
+    }
 }
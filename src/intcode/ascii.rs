@@ -0,0 +1,133 @@
+use super::{IntCodeError, IntCodeEvent, IntCodeState};
+
+// Output of a text-mode program. Anything outside the ASCII range, such as
+// a puzzle answer, goes in `values` rather than `text`.
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct AsciiOutput {
+    pub text: String,
+    pub values: Vec<i64>,
+    // Whether the program halted, rather than stopping to wait for input
+    pub halted: bool,
+}
+
+impl AsciiOutput {
+    pub fn push(&mut self, value: i64) {
+        match u8::try_from(value) {
+            Ok(c) if c.is_ascii() => self.text.push(c as char),
+            _ => self.values.push(value),
+        }
+    }
+}
+
+pub fn decode<I: IntoIterator<Item = i64>>(values: I) -> AsciiOutput {
+    let mut output = AsciiOutput::default();
+    values.into_iter().for_each(|v| output.push(v));
+    output
+}
+
+pub fn encode_line(line: &str) -> impl Iterator<Item = i64> + '_ {
+    line.bytes().chain(Some(b'\n')).map(i64::from)
+}
+
+// Line-oriented access to a program's input and output
+pub struct Ascii<'a> {
+    state: &'a mut IntCodeState,
+}
+
+impl IntCodeState {
+    pub fn ascii(&mut self) -> Ascii<'_> {
+        Ascii { state: self }
+    }
+}
+
+impl Ascii<'_> {
+    // Queues the line for the program, adding the newline
+    pub fn send_line(&mut self, line: &str) {
+        self.state.in_buffer.extend(encode_line(line));
+    }
+
+    // Runs until the program wants input which hasn't been sent, or halts,
+    // returning everything it output including anything already in
+    // out_buffer
+    pub fn try_read_until_prompt(&mut self) -> Result<AsciiOutput, IntCodeError> {
+        let mut output = decode(self.state.out_buffer.drain(..));
+        loop {
+            match self.state.try_run_until_event()? {
+                IntCodeEvent::Output(value) => output.push(value),
+                IntCodeEvent::NeedsInput => return Ok(output),
+                IntCodeEvent::Halted => {
+                    output.halted = true;
+                    return Ok(output);
+                }
+            }
+        }
+    }
+
+    pub fn read_until_prompt(&mut self) -> AsciiOutput {
+        self.try_read_until_prompt()
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let output = decode([72, 105, 10, 128, -1, 1000, 33]);
+        assert_eq!(output.text, "Hi\n!");
+        assert_eq!(output.values, vec![128, -1, 1000]);
+        assert!(!output.halted);
+    }
+
+    #[test]
+    fn test_conversation() {
+        // Prompts with "?", then echoes lines until one is empty, and
+        // outputs 200 plus how many characters it read
+        let program = assemble(
+            "
+                    out #63
+                    out #10
+            loop:   in c
+                    eq c, #10, t
+                    jt t, #eol
+                    add n, #1, n
+                    add line, #1, line
+                    out c
+                    jt #1, #loop
+            eol:    out #10
+                    jf line, #done
+                    add #0, #0, line
+                    jt #1, #loop
+            done:   out n
+                    hlt
+            c:      data 0
+            t:      data 0
+            n:      data 200
+            line:   data 0
+            ",
+        )
+        .unwrap();
+        let mut prog: IntCodeState = program.into();
+        let mut ascii = prog.ascii();
+
+        let prompt = ascii.read_until_prompt();
+        assert_eq!(prompt.text, "?\n");
+        assert!(!prompt.halted);
+
+        ascii.send_line("hello");
+        ascii.send_line("é");
+        let output = ascii.read_until_prompt();
+        assert_eq!(output.text, "hello\n\n");
+        assert_eq!(output.values, vec![0xc3, 0xa9]);
+        assert!(!output.halted);
+
+        ascii.send_line("");
+        let output = ascii.read_until_prompt();
+        assert_eq!(output.text, "\n");
+        assert_eq!(output.values, vec![207]);
+        assert!(output.halted);
+    }
+}
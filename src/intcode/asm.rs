@@ -0,0 +1,359 @@
+use super::disasm::{DecodedInstruction, Opcode, Operand};
+use super::memory::MAX_DENSE_MEMORY;
+use ahash::AHashMap;
+use std::error::Error;
+use std::fmt;
+
+// Source format, one statement per line, `;` starts a comment:
+//
+//   const LIMIT = 8       ; named constant
+//   start: in value       ; label, then instruction
+//          lt value, #LIMIT, @-1
+//          jt #1, #start
+//   value: data 0, 1, -2  ; literal words
+//          zeros 4        ; 4 words of zero
+//
+// Operands are position mode by default, `#` for immediate and `@` for
+// relative. Any value can be an expression of numbers, labels and constants
+// joined by `+` and `-`. A numeric label such as `12:` asserts the current
+// address, so that disassembler listings can be assembled again.
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for AssembleError {}
+
+enum Statement<'a> {
+    Instruction(Opcode, Vec<&'a str>),
+    Data(Vec<&'a str>),
+    Zeros(usize),
+}
+
+struct Line<'a> {
+    number: usize,
+    address: usize,
+    statement: Statement<'a>,
+}
+
+fn err<T>(line: usize, message: String) -> Result<T, AssembleError> {
+    Err(AssembleError { line, message })
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn split_args(args: &str) -> Vec<&str> {
+    if args.is_empty() {
+        vec![]
+    } else {
+        args.split(',').map(|a| a.trim()).collect()
+    }
+}
+
+fn overflow(expr: &str, line: usize) -> AssembleError {
+    AssembleError {
+        line,
+        message: format!("expression '{}' overflows", expr.trim()),
+    }
+}
+
+fn evaluate(expr: &str, symbols: &AHashMap<&str, i64>, line: usize) -> Result<i64, AssembleError> {
+    let mut total: i64 = 0;
+    let mut sign = 1;
+    let mut rest = expr.trim();
+
+    loop {
+        // Skip the first character, which may be a negative number's sign
+        let end = rest
+            .char_indices()
+            .skip(1)
+            .find(|&(_, c)| c == '+' || c == '-')
+            .map(|(i, _)| i)
+            .unwrap_or(rest.len());
+        let term = rest[..end].trim();
+
+        let value = if let Ok(num) = term.parse::<i64>() {
+            num
+        } else if let Some(&value) = symbols.get(term) {
+            value
+        } else if let Some(name) = term.strip_prefix('-').map(|t| t.trim()) {
+            match symbols.get(name) {
+                Some(&value) => value.checked_neg().ok_or_else(|| overflow(expr, line))?,
+                None => return err(line, format!("unknown symbol '{}'", name)),
+            }
+        } else if term.is_empty() {
+            return err(line, format!("invalid expression '{}'", expr));
+        } else {
+            return err(line, format!("unknown symbol '{}'", term));
+        };
+        total = if sign < 0 {
+            total.checked_sub(value)
+        } else {
+            total.checked_add(value)
+        }
+        .ok_or_else(|| overflow(expr, line))?;
+
+        rest = rest[end..].trim_start();
+        if rest.is_empty() {
+            return Ok(total);
+        }
+        sign = if rest.starts_with('-') { -1 } else { 1 };
+        rest = rest[1..].trim_start();
+        if rest.is_empty() {
+            return err(line, format!("invalid expression '{}'", expr));
+        }
+    }
+}
+
+fn parse_operand(
+    operand: &str,
+    symbols: &AHashMap<&str, i64>,
+    line: usize,
+) -> Result<Operand, AssembleError> {
+    if let Some(expr) = operand.strip_prefix('#') {
+        Ok(Operand::Immediate(evaluate(expr, symbols, line)?))
+    } else if let Some(expr) = operand.strip_prefix('@') {
+        Ok(Operand::Relative(evaluate(expr, symbols, line)?))
+    } else {
+        Ok(Operand::Position(evaluate(operand, symbols, line)?))
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Vec<i64>, AssembleError> {
+    let mut symbols: AHashMap<&str, i64> = AHashMap::default();
+    let mut lines = vec![];
+    let mut address: usize = 0;
+
+    // First pass: work out the address of every statement and label
+    for (idx, raw_line) in source.lines().enumerate() {
+        let number = idx + 1;
+        let mut text = raw_line.split(';').next().unwrap_or("").trim();
+
+        if let Some(constant) = text.strip_prefix("const ") {
+            let Some((name, expr)) = constant.split_once('=') else {
+                return err(number, "expected 'const NAME = value'".to_string());
+            };
+            let name = name.trim();
+            if !is_identifier(name) || symbols.contains_key(name) {
+                return err(number, format!("invalid or duplicate name '{}'", name));
+            }
+            symbols.insert(name, evaluate(expr, &symbols, number)?);
+            continue;
+        }
+
+        while let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if let Ok(expected) = label.parse::<usize>() {
+                if expected != address {
+                    return err(
+                        number,
+                        format!("address {} expected but at {}", expected, address),
+                    );
+                }
+            } else if is_identifier(label) && !symbols.contains_key(label) {
+                symbols.insert(label, address as i64);
+            } else {
+                return err(number, format!("invalid or duplicate label '{}'", label));
+            }
+            text = rest.trim();
+        }
+
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let args = split_args(args.trim());
+
+        let statement = match mnemonic {
+            "data" => {
+                address += args.len();
+                Statement::Data(args)
+            }
+            "zeros" => {
+                let [count] = args[..] else {
+                    return err(number, "zeros takes one argument".to_string());
+                };
+                let Ok(count_value) = usize::try_from(evaluate(count, &symbols, number)?) else {
+                    return err(number, format!("invalid zeros count '{}'", count));
+                };
+                // No bigger than the VM would let the program grow anyway
+                address = match address.checked_add(count_value) {
+                    Some(end) if end <= MAX_DENSE_MEMORY => end,
+                    _ => {
+                        return err(
+                            number,
+                            format!(
+                                "zeros {} would make the program longer than {} words",
+                                count, MAX_DENSE_MEMORY
+                            ),
+                        )
+                    }
+                };
+                Statement::Zeros(count_value)
+            }
+            _ => {
+                let Some(opcode) = Opcode::from_mnemonic(mnemonic) else {
+                    return err(number, format!("unknown mnemonic '{}'", mnemonic));
+                };
+                if args.len() != opcode.arity() {
+                    return err(
+                        number,
+                        format!(
+                            "{} takes {} operands, got {}",
+                            mnemonic,
+                            opcode.arity(),
+                            args.len()
+                        ),
+                    );
+                }
+                address += 1 + opcode.arity();
+                Statement::Instruction(opcode, args)
+            }
+        };
+
+        lines.push(Line {
+            number,
+            address,
+            statement,
+        });
+    }
+
+    // Second pass: now every label is known, emit the words
+    let mut program = Vec::with_capacity(address);
+    for line in lines {
+        match line.statement {
+            Statement::Instruction(opcode, args) => {
+                let operands = args
+                    .iter()
+                    .map(|arg| parse_operand(arg, &symbols, line.number))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if let Some(write) = opcode.write_operand() {
+                    if let Operand::Immediate(_) = operands[write] {
+                        return err(line.number, "can't write to an immediate".to_string());
+                    }
+                }
+                program.extend(
+                    DecodedInstruction {
+                        address: program.len(),
+                        opcode,
+                        operands,
+                    }
+                    .encode(),
+                );
+            }
+            Statement::Data(args) => {
+                for arg in args {
+                    program.push(evaluate(arg, &symbols, line.number)?);
+                }
+            }
+            Statement::Zeros(count) => program.resize(program.len() + count, 0),
+        }
+        debug_assert_eq!(program.len(), line.address);
+    }
+
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::disasm::disassemble_to_string;
+    use super::super::IntCodeState;
+    use super::*;
+
+    #[test]
+    fn test_assemble_eq_8() {
+        let source = "
+            const EIGHT = 8
+                  in value
+                  eq value, eight, value   ; compare with the constant stored below
+                  out value
+                  hlt
+            value: data -1
+            eight: data EIGHT
+        ";
+        assert_eq!(
+            assemble(source),
+            Ok(vec![3, 9, 8, 9, 10, 9, 4, 9, 99, -1, 8])
+        );
+    }
+
+    #[test]
+    fn test_assemble_modes_and_expressions() {
+        let source = "
+            start: arb #buffer+1
+                   add @-1, #-3, buffer - 2
+                   jt #1, #start
+            buffer: zeros 2
+        ";
+        assert_eq!(
+            assemble(source),
+            Ok(vec![109, 10, 1201, -1, -3, 7, 1105, 1, 0, 0, 0])
+        );
+    }
+
+    #[test]
+    fn test_assembled_program_runs() {
+        let source = "
+                  in counter
+            loop: out counter
+                  add counter, #-1, counter
+                  jt counter, #loop
+                  hlt
+            counter: data 0
+        ";
+        let mut prog: IntCodeState = assemble(source).unwrap().into();
+        prog.in_buffer.push_back(3);
+        prog.execute_until_halt(|s| s.in_buffer.pop_front());
+        assert_eq!(prog.out_buffer, vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        let quine = vec![
+            109, 1, 204, -1, 1001, 100, 1, 100, 1008, 100, 16, 101, 1006, 101, 0, 99,
+        ];
+        let listing = disassemble_to_string(&quine);
+        assert_eq!(assemble(&listing), Ok(quine));
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert_eq!(assemble("add 1, 2").unwrap_err().line, 1);
+        assert_eq!(assemble("\nadd 1, 2, #3").unwrap_err().line, 2);
+        assert_eq!(assemble("jt #1, #nowhere").unwrap_err().line, 1);
+        assert_eq!(assemble("a: hlt\na: hlt").unwrap_err().line, 2);
+        assert_eq!(assemble("hlt\n0: hlt").unwrap_err().line, 2);
+        assert_eq!(assemble("nop").unwrap_err().line, 1);
+        assert_eq!(assemble("out #").unwrap_err().line, 1);
+        assert_eq!(assemble("out 1 +").unwrap_err().line, 1);
+        let overflow = assemble("hlt\nout #9223372036854775807 + 1").unwrap_err();
+        assert_eq!(overflow.line, 2);
+        assert!(overflow.message.contains("overflows"));
+        assert_eq!(
+            assemble("const M = -9223372036854775808\nout -M")
+                .unwrap_err()
+                .line,
+            2
+        );
+        assert_eq!(
+            assemble("zeros 4611686018427387904\nhlt").unwrap_err().line,
+            1
+        );
+        let huge = "zeros 9223372036854775807\nzeros 9223372036854775807";
+        assert_eq!(assemble(huge).unwrap_err().line, 1);
+        assert_eq!(assemble("zeros 67108860\nzeros 5").unwrap_err().line, 2);
+    }
+}
@@ -0,0 +1,122 @@
+use super::{IntCodeError, IntCodeEvent, IntCodeState};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum AsyncRunError<E> {
+    IntCode(IntCodeError),
+    // The program wanted input after the input stream had ended
+    InputClosed,
+    Output(E),
+}
+
+impl<E: fmt::Display> fmt::Display for AsyncRunError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsyncRunError::IntCode(e) => write!(f, "{}", e),
+            AsyncRunError::InputClosed => write!(f, "input closed while waiting for input"),
+            AsyncRunError::Output(e) => write!(f, "can't send output: {}", e),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for AsyncRunError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AsyncRunError::IntCode(e) => Some(e),
+            AsyncRunError::InputClosed => None,
+            AsyncRunError::Output(e) => Some(e),
+        }
+    }
+}
+
+impl IntCodeState {
+    // Runs until the program halts. Input is taken from in_buffer first,
+    // then awaited from `input`; outputs are sent to `output` rather than
+    // left in out_buffer. Only input and output are await points, so a
+    // program doing neither keeps the executor to itself until it does.
+    //
+    // Streams and sinks which are Unpin can be lent as `&mut`, to carry on
+    // using them once the program halts.
+    pub async fn try_run_async<I, O>(
+        &mut self,
+        mut input: I,
+        mut output: O,
+    ) -> Result<(), AsyncRunError<O::Error>>
+    where
+        I: Stream<Item = i64> + Unpin,
+        O: Sink<i64> + Unpin,
+    {
+        loop {
+            match self.try_run_until_event().map_err(AsyncRunError::IntCode)? {
+                IntCodeEvent::NeedsInput => match input.next().await {
+                    Some(value) => self.in_buffer.push_back(value),
+                    None => return Err(AsyncRunError::InputClosed),
+                },
+                IntCodeEvent::Output(value) => {
+                    output.send(value).await.map_err(AsyncRunError::Output)?
+                }
+                IntCodeEvent::Halted => return Ok(()),
+            }
+        }
+    }
+
+    pub async fn run_async<I, O>(&mut self, input: I, output: O)
+    where
+        I: Stream<Item = i64> + Unpin,
+        O: Sink<i64> + Unpin,
+        O::Error: fmt::Display,
+    {
+        self.try_run_async(input, output)
+            .await
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::*;
+    use futures::channel::mpsc;
+    use futures::executor::block_on;
+    use futures::future::join;
+    use futures::stream;
+
+    #[test]
+    fn test_pipeline() {
+        // Adds one to each input until it reads a 0
+        let software = assemble(
+            "
+            loop:   in x
+                    jt x, #inc
+                    hlt
+            inc:    add x, #1, x
+                    out x
+                    jt #1, #loop
+            x:      data 0
+            ",
+        )
+        .unwrap();
+        let (mut first, mut second): (IntCodeState, IntCodeState) =
+            (software.clone().into(), software.into());
+        let (tx, rx) = mpsc::unbounded();
+        let (mut out_tx, out_rx) = mpsc::unbounded();
+
+        block_on(join(
+            first.run_async(stream::iter([1, 2, 3, 0]), tx),
+            second.run_async(rx.chain(stream::iter([0])), &mut out_tx),
+        ));
+        out_tx.close_channel();
+        assert_eq!(block_on(out_rx.collect::<Vec<_>>()), vec![3, 4, 5]);
+        assert!(first.out_buffer.is_empty());
+    }
+
+    #[test]
+    fn test_input_closed() {
+        let mut prog: IntCodeState = vec![3, 0, 99].into();
+        let (tx, _rx) = mpsc::unbounded();
+        let result = block_on(prog.try_run_async(stream::empty(), tx));
+        assert!(matches!(result, Err(AsyncRunError::InputClosed)));
+    }
+}
@@ -0,0 +1,379 @@
+use super::disasm::{decode_instruction, DecodedInstruction, Opcode, Operand};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum EdgeKind {
+    FallThrough,
+    Jump,
+    // From a call site to the function called
+    Call,
+    // From a call site to where the function returns to
+    CallReturn,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub instructions: Vec<DecodedInstruction>,
+    // Ends by jumping to a return address stored relative to the base
+    pub returns: bool,
+    // Ends with a jump whose target isn't known statically, other than a return
+    pub indirect_jump: bool,
+}
+
+impl BasicBlock {
+    // One past the last word of the block
+    pub fn end(&self) -> usize {
+        self.instructions
+            .last()
+            .map_or(self.start, |ins| ins.address + ins.size())
+    }
+
+    pub fn last(&self) -> &DecodedInstruction {
+        self.instructions.last().expect("blocks are never empty")
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct ControlFlowGraph {
+    pub blocks: BTreeMap<usize, BasicBlock>,
+    pub edges: BTreeSet<Edge>,
+    // Entry points of functions, found from their call sites
+    pub functions: BTreeSet<usize>,
+    // Instructions which the program may overwrite, mapped to the addresses
+    // of the instructions which write to them. Only writes to fixed
+    // addresses are found, not those relative to the base.
+    pub self_modified: BTreeMap<usize, BTreeSet<usize>>,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub(super) struct Call {
+    pub target: usize,
+    pub return_site: usize,
+}
+
+fn unconditional(ins: &DecodedInstruction) -> bool {
+    ins.can_jump() && !ins.can_fall_through()
+}
+
+fn constant_written(ins: &DecodedInstruction) -> Option<i64> {
+    match (ins.opcode, &ins.operands[..]) {
+        (Opcode::Add, [Operand::Immediate(x), Operand::Immediate(y), _]) => x.checked_add(*y),
+        (Opcode::Mul, [Operand::Immediate(x), Operand::Immediate(y), _]) => x.checked_mul(*y),
+        _ => None,
+    }
+}
+
+// A call stores the return address relative to the base, then jumps
+// unconditionally to the function:
+//
+//   add #return_site, #0, @k
+//   jt #1, #target
+//
+// Returns the call made by the jump, given the instruction before it.
+pub(super) fn call_from(
+    program: &[i64],
+    store: &DecodedInstruction,
+    jump: &DecodedInstruction,
+) -> Option<Call> {
+    if !matches!(store.operands.last(), Some(Operand::Relative(_))) || !unconditional(jump) {
+        return None;
+    }
+    let return_site = usize::try_from(constant_written(store)?).ok()?;
+    let target = usize::try_from(jump.jump_target()?).ok()?;
+    (return_site < program.len() && target < program.len()).then_some(Call {
+        target,
+        return_site,
+    })
+}
+
+fn is_return(ins: &DecodedInstruction) -> bool {
+    unconditional(ins) && matches!(ins.operands.get(1), Some(Operand::Relative(_)))
+}
+
+impl ControlFlowGraph {
+    // Follows everything reachable from address 0, including where calls
+    // return to.
+    pub fn build(program: &[i64]) -> Self {
+        let mut covered = vec![false; program.len()];
+        let mut code: BTreeMap<usize, DecodedInstruction> = BTreeMap::new();
+        let mut leaders = BTreeSet::from([0]);
+        let mut calls: BTreeMap<usize, Call> = BTreeMap::new();
+        let mut to_visit = vec![0];
+
+        while let Some(address) = to_visit.pop() {
+            let Some(ins) = decode_instruction(program, address) else {
+                continue;
+            };
+            if covered[address..address + ins.size()].iter().any(|&c| c) {
+                continue;
+            }
+            covered[address..address + ins.size()].fill(true);
+
+            let next = address + ins.size();
+            if ins.can_fall_through() {
+                to_visit.push(next);
+                if let Some(jump) = decode_instruction(program, next) {
+                    if let Some(call) = call_from(program, &ins, &jump) {
+                        calls.insert(next, call);
+                        leaders.insert(call.return_site);
+                        to_visit.push(call.return_site);
+                    }
+                }
+            }
+            if ins.can_jump() {
+                leaders.insert(next);
+                if let Some(target) = ins.jump_target().and_then(|t| usize::try_from(t).ok()) {
+                    leaders.insert(target);
+                    to_visit.push(target);
+                }
+            }
+            if ins.opcode == Opcode::Halt {
+                leaders.insert(next);
+            }
+            code.insert(address, ins);
+        }
+
+        let mut cfg = ControlFlowGraph {
+            functions: calls.values().map(|call| call.target).collect(),
+            ..Default::default()
+        };
+
+        let mut current: Option<BasicBlock> = None;
+        for (&address, ins) in code.iter() {
+            let continues = current
+                .as_ref()
+                .is_some_and(|block| block.end() == address && !leaders.contains(&address));
+            if !continues {
+                if let Some(block) = current.take() {
+                    cfg.add_block(block, &code, &calls);
+                }
+                current = Some(BasicBlock {
+                    start: address,
+                    instructions: vec![],
+                    returns: false,
+                    indirect_jump: false,
+                });
+            }
+            let block = current.as_mut().expect("block was just started");
+            block.instructions.push(ins.clone());
+            if ins.can_jump() || ins.opcode == Opcode::Halt {
+                cfg.add_block(current.take().expect("block exists"), &code, &calls);
+            }
+        }
+        if let Some(block) = current {
+            cfg.add_block(block, &code, &calls);
+        }
+
+        for ins in code.values() {
+            let Some(Operand::Position(target)) =
+                ins.opcode.write_operand().map(|idx| ins.operands[idx])
+            else {
+                continue;
+            };
+            let written = code
+                .range(..=target.max(0) as usize)
+                .next_back()
+                .filter(|(&a, w)| target >= 0 && (target as usize) < a + w.size());
+            if let Some((&written, _)) = written {
+                cfg.self_modified
+                    .entry(written)
+                    .or_default()
+                    .insert(ins.address);
+            }
+        }
+
+        cfg
+    }
+
+    fn add_block(
+        &mut self,
+        mut block: BasicBlock,
+        code: &BTreeMap<usize, DecodedInstruction>,
+        calls: &BTreeMap<usize, Call>,
+    ) {
+        let last = block.last().clone();
+        let from = block.start;
+        let next = block.end();
+
+        if last.can_fall_through() && code.contains_key(&next) {
+            self.edges.insert(Edge {
+                from,
+                to: next,
+                kind: EdgeKind::FallThrough,
+            });
+        }
+        if let Some(call) = calls.get(&last.address) {
+            self.edges.insert(Edge {
+                from,
+                to: call.target,
+                kind: EdgeKind::Call,
+            });
+            self.edges.insert(Edge {
+                from,
+                to: call.return_site,
+                kind: EdgeKind::CallReturn,
+            });
+        } else if last.can_jump() {
+            match last.jump_target().and_then(|t| usize::try_from(t).ok()) {
+                Some(target) if code.contains_key(&target) => {
+                    self.edges.insert(Edge {
+                        from,
+                        to: target,
+                        kind: EdgeKind::Jump,
+                    });
+                }
+                Some(_) => {}
+                None if is_return(&last) => block.returns = true,
+                None => block.indirect_jump = true,
+            }
+        }
+        self.blocks.insert(from, block);
+    }
+
+    pub fn successors(&self, block: usize) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |edge| edge.from == block)
+    }
+
+    pub fn predecessors(&self, block: usize) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |edge| edge.to == block)
+    }
+
+    // Graphviz source. Function entries have a double border, instructions
+    // which may be overwritten are marked with `*`, and calls are dashed.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph intcode {{").unwrap();
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            for ins in block.instructions.iter() {
+                let marker = if self.self_modified.contains_key(&ins.address) {
+                    "*"
+                } else {
+                    " "
+                };
+                write!(label, "{}{:>5}: {}\\l", marker, ins.address, ins).unwrap();
+            }
+            if block.returns {
+                label.push_str("  return\\l");
+            }
+            if block.indirect_jump {
+                label.push_str("  indirect jump\\l");
+            }
+            let peripheries = if self.functions.contains(&block.start) {
+                2
+            } else {
+                1
+            };
+            writeln!(
+                out,
+                "    b{} [label=\"{}\", peripheries={}];",
+                block.start, label, peripheries
+            )
+            .unwrap();
+        }
+        for edge in self.edges.iter() {
+            let style = match edge.kind {
+                EdgeKind::FallThrough => "",
+                EdgeKind::Jump => " [color=blue]",
+                EdgeKind::Call => " [style=dashed, label=\"call\"]",
+                EdgeKind::CallReturn => " [style=dotted]",
+            };
+            writeln!(out, "    b{} -> b{}{};", edge.from, edge.to, style).unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::*;
+
+    fn edge(from: usize, to: usize, kind: EdgeKind) -> Edge {
+        Edge { from, to, kind }
+    }
+
+    #[test]
+    fn test_loop() {
+        // out 20; add 20, #-1, 20; jt 20, #0; hlt
+        let program = [4, 20, 1001, 20, -1, 20, 1005, 20, 0, 99];
+        let cfg = ControlFlowGraph::build(&program);
+        assert_eq!(cfg.blocks.keys().copied().collect::<Vec<_>>(), vec![0, 9]);
+        assert_eq!(cfg.blocks[&0].instructions.len(), 3);
+        assert_eq!(
+            cfg.edges,
+            BTreeSet::from([
+                edge(0, 9, EdgeKind::FallThrough),
+                edge(0, 0, EdgeKind::Jump)
+            ])
+        );
+        assert!(cfg.functions.is_empty());
+        assert!(cfg.self_modified.is_empty());
+    }
+
+    #[test]
+    fn test_overflowing_return_site() {
+        // add #MAX, #1, @0; jt #1, #7; hlt
+        let program = [21101, i64::MAX, 1, 0, 1105, 1, 7, 99];
+        let cfg = ControlFlowGraph::build(&program);
+        assert!(cfg.functions.is_empty());
+        assert_eq!(cfg.blocks.keys().copied().collect::<Vec<_>>(), vec![0, 7]);
+    }
+
+    #[test]
+    fn test_calls_and_self_modification() {
+        let program = assemble(
+            "
+            main:   arb #stack
+                    add #2, #0, func + 2
+                    add #after, #0, @0
+                    jt #1, #func
+            after:  out value
+                    hlt
+            func:   add value, #1, value
+                    jf #0, @0
+            value:  data 41
+            stack:  zeros 4
+            ",
+        )
+        .unwrap();
+        let cfg = ControlFlowGraph::build(&program);
+        let (after, func) = (13, 16);
+
+        assert_eq!(
+            cfg.blocks.keys().copied().collect::<Vec<_>>(),
+            vec![0, after, func]
+        );
+        assert_eq!(
+            cfg.successors(0).copied().collect::<Vec<_>>(),
+            vec![
+                edge(0, after, EdgeKind::CallReturn),
+                edge(0, func, EdgeKind::Call)
+            ]
+        );
+        assert_eq!(cfg.functions, BTreeSet::from([func]));
+        assert!(cfg.blocks[&func].returns);
+        assert!(!cfg.blocks[&after].returns);
+        assert_eq!(
+            cfg.self_modified,
+            BTreeMap::from([(func, BTreeSet::from([2]))])
+        );
+
+        let dot = cfg.to_dot();
+        assert!(dot.starts_with("digraph intcode {\n"));
+        assert!(dot.contains("b0 -> b16 [style=dashed, label=\"call\"];"));
+        assert!(dot.contains("*   16: add 23, #1, 23\\l"));
+        assert!(dot.contains("peripheries=2"));
+    }
+}
@@ -0,0 +1,302 @@
+use super::{Instruction, IntCodeError, IntCodeErrorKind, IntCodeState, Step};
+use ahash::AHashMap;
+use std::fmt;
+use std::sync::Arc;
+
+// Extra instructions for experimenting with Intcode dialects. They are only
+// looked up when an opcode isn't one of the built-in ones, which already
+// leaves the pre-decoded fast path, so they cost the built-ins nothing.
+// Custom instructions are decoded every time they run. They show up in
+// traces, profiles and watches like any other instruction, but as a handler
+// can change anything about the VM, they can't be undone, so undo history
+// can't be enabled while any are registered.
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum OperandKind {
+    // The handler gets the operand's value, in any mode
+    Read,
+    // The handler gets the address the operand refers to, which is checked
+    // to be writable. Immediate mode is an error.
+    Write,
+}
+
+// What the VM does once the handler returns
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum CustomStep {
+    Continue,
+    Jump(i64),
+    Output(i64),
+    // Leaves the instruction pointer where it is, to retry the instruction
+    // once more input has arrived
+    Blocked,
+    Halt,
+}
+
+// Given the value or address of each operand, in order. A handler which
+// returns an error must do so before changing anything, so that the failed
+// instruction leaves the VM as it found it, as built-in instructions do.
+pub type CustomHandler = fn(&mut IntCodeState, &[i64]) -> Result<CustomStep, IntCodeError>;
+
+#[derive(Clone)]
+pub struct CustomOpcode {
+    pub code: u32,
+    pub operands: Vec<OperandKind>,
+    pub handler: CustomHandler,
+}
+
+impl fmt::Debug for CustomOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CustomOpcode")
+            .field("code", &self.code)
+            .field("operands", &self.operands)
+            .finish()
+    }
+}
+
+// Shared between clones, as a forked VM speaks the same dialect. Like the
+// instruments, the table isn't compared along with the VM's state.
+#[derive(Clone, Default)]
+pub(super) struct CustomOpcodes(Option<Arc<AHashMap<u32, CustomOpcode>>>);
+
+impl PartialEq for CustomOpcodes {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for CustomOpcodes {}
+
+impl fmt::Debug for CustomOpcodes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(table) => f.debug_list().entries(table.keys()).finish(),
+            None => f.write_str("None"),
+        }
+    }
+}
+
+impl CustomOpcodes {
+    pub fn get(&self, code: u32) -> Option<&CustomOpcode> {
+        self.0.as_ref().and_then(|table| table.get(&code))
+    }
+
+    pub fn max_operands(&self) -> usize {
+        self.0
+            .as_ref()
+            .and_then(|table| table.values().map(|op| op.operands.len()).max())
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+const BUILT_IN: [u32; 10] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 99];
+
+impl IntCodeState {
+    // Adds an instruction, replacing any custom one with the same code.
+    // Panics if the code is one of the built-in opcodes or isn't below 100,
+    // or if undo history is enabled.
+    pub fn register_opcode(&mut self, opcode: CustomOpcode) {
+        assert!(
+            opcode.code < 100 && !BUILT_IN.contains(&opcode.code),
+            "can't register opcode {}",
+            opcode.code
+        );
+        assert!(
+            !self.has_history(),
+            "can't register opcode {} with undo history enabled",
+            opcode.code
+        );
+        let table = self.custom_opcodes.0.get_or_insert_with(Default::default);
+        Arc::make_mut(table).insert(opcode.code, opcode);
+    }
+
+    pub fn with_opcode(
+        mut self,
+        code: u32,
+        operands: &[OperandKind],
+        handler: CustomHandler,
+    ) -> Self {
+        self.register_opcode(CustomOpcode {
+            code,
+            operands: operands.to_vec(),
+            handler,
+        });
+        self
+    }
+
+    pub(super) fn step_custom(&mut self, instruction: &Instruction) -> Result<Step, IntCodeError> {
+        let opcode = match self.custom_opcodes.get(instruction.typ()) {
+            Some(opcode) => opcode.clone(),
+            None => {
+                let kind = IntCodeErrorKind::InvalidOpcode(instruction.typ() as i64);
+                return Err(self.fault(kind, None));
+            }
+        };
+
+        let mut args = Vec::with_capacity(opcode.operands.len());
+        for (idx, kind) in opcode.operands.iter().enumerate() {
+            let mode = instruction.mode(idx);
+            let offset = idx as i64 + 1;
+            args.push(match kind {
+                OperandKind::Read => self.get_parameter(mode, offset)?,
+                OperandKind::Write => self.parameter_address(mode, offset)?,
+            });
+        }
+
+        let next = self.offset(self.instruction_ptr, 1 + args.len() as i64)?;
+        Ok(match (opcode.handler)(self, &args)? {
+            CustomStep::Continue => {
+                self.instruction_ptr = next;
+                Step::Continue
+            }
+            CustomStep::Jump(address) => {
+                self.instruction_ptr = address;
+                Step::Continue
+            }
+            CustomStep::Output(value) => {
+                self.out_buffer.push_back(value);
+                self.instruction_ptr = next;
+                Step::Output
+            }
+            CustomStep::Blocked => Step::Blocked,
+            CustomStep::Halt => Step::Halted,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::disasm::Opcode;
+    use super::super::trace::{MemoryWrite, TraceBuffer};
+    use super::super::IntCodeEvent;
+    use super::*;
+    use OperandKind::{Read, Write};
+
+    fn min(_: &mut IntCodeState, args: &[i64]) -> Result<CustomStep, IntCodeError> {
+        Ok(CustomStep::Output(args[0].min(args[1])))
+    }
+
+    // Stores the sum of all the input, once there is some
+    fn sum_input(prog: &mut IntCodeState, args: &[i64]) -> Result<CustomStep, IntCodeError> {
+        if prog.in_buffer.is_empty() {
+            return Ok(CustomStep::Blocked);
+        }
+        let total = prog.in_buffer.drain(..).sum();
+        prog.set_mem(args[0], total);
+        Ok(CustomStep::Continue)
+    }
+
+    // Jumps to the first operand if the second is the third
+    fn jump_eq(_: &mut IntCodeState, args: &[i64]) -> Result<CustomStep, IntCodeError> {
+        Ok(if args[1] == args[2] {
+            CustomStep::Jump(args[0])
+        } else {
+            CustomStep::Continue
+        })
+    }
+
+    fn dialect(program: Vec<i64>) -> IntCodeState {
+        IntCodeState::from(program)
+            .with_opcode(10, &[Read, Read], min)
+            .with_opcode(11, &[Write], sum_input)
+            .with_opcode(12, &[Read, Read, Read], jump_eq)
+    }
+
+    #[test]
+    fn test_custom_opcodes() {
+        let mut prog = dialect(vec![
+            109, 20, // arb #20
+            1110, 7, 3, // min #7, #3
+            211, 1, // sum rel[1]
+            210, 1, 2, // min rel[1], mem[2]
+            10112, 15, 21, 4,  // jeq #15, mem[21], #4
+            99, // hlt
+            1104, -1, // out #-1
+            99,
+        ]);
+        assert_eq!(prog.run_until_event(), IntCodeEvent::Output(3));
+        assert_eq!(prog.run_until_event(), IntCodeEvent::NeedsInput);
+        prog.in_buffer.extend([1, 3]);
+        assert_eq!(prog.run_until_event(), IntCodeEvent::Output(4));
+        assert_eq!(prog.run_until_event(), IntCodeEvent::Output(-1));
+        assert_eq!(prog.run_until_event(), IntCodeEvent::Halted);
+
+        // Forks share the dialect
+        let mut fork = prog.fork();
+        fork.set_instruction_ptr(2);
+        assert_eq!(fork.run_until_event(), IntCodeEvent::Output(3));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut prog = dialect(vec![13, 99]);
+        let error = prog.try_execute_single_step(|_| None).unwrap_err();
+        assert_eq!(error.kind, IntCodeErrorKind::InvalidOpcode(13));
+        assert_eq!(error.instruction, Some(13));
+
+        let mut prog = dialect(vec![111, 0, 99]);
+        let error = prog.try_execute_single_step(|_| None).unwrap_err();
+        assert_eq!(error.kind, IntCodeErrorKind::ImmediateModeWrite);
+        assert_eq!(prog.instruction_ptr(), 0);
+    }
+
+    #[test]
+    fn test_instrumented() {
+        let mut prog = dialect(vec![1110, 7, 3, 11, 20, 99]);
+        let buffer = TraceBuffer::new(10);
+        prog.start_trace(buffer.clone());
+        prog.start_profile();
+        assert_eq!(prog.run_until_event(), IntCodeEvent::Output(3));
+        prog.in_buffer.extend([4, 5]);
+        assert_eq!(prog.run_until_event(), IntCodeEvent::Halted);
+
+        let records = buffer.records();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].instruction.opcode, Opcode::Custom(10));
+        assert_eq!(
+            records[0].to_string(),
+            "    0: op10 #7, #3                  (7, 3)"
+        );
+        assert_eq!(records[1].values, vec![20]);
+        assert_eq!(
+            records[1].write,
+            Some(MemoryWrite {
+                address: 20,
+                old: 0,
+                new: 9
+            })
+        );
+
+        let profile = prog.stop_profile().unwrap();
+        assert_eq!(profile.count_of(Opcode::Custom(11)), 1);
+        assert_eq!(profile.steps(), 3);
+        assert!(profile.report(&prog, 5).contains("op11 20"));
+    }
+
+    #[test]
+    #[should_panic(expected = "can't enable undo history")]
+    fn test_no_history() {
+        dialect(vec![99]).enable_history(10);
+    }
+
+    #[test]
+    #[should_panic(expected = "with undo history enabled")]
+    fn test_no_custom_with_history() {
+        let mut prog: IntCodeState = vec![99].into();
+        prog.enable_history(10);
+        prog.register_opcode(CustomOpcode {
+            code: 10,
+            operands: vec![Read, Read],
+            handler: min,
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "can't register opcode 4")]
+    fn test_built_in_clash() {
+        dialect(vec![99]).with_opcode(4, &[Read], min);
+    }
+}
@@ -0,0 +1,520 @@
+use super::cfg::{BasicBlock, ControlFlowGraph, EdgeKind};
+use super::disasm::{DecodedInstruction, Opcode, Operand};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Write;
+
+// Turns a program into C-like pseudocode, one function for the code from
+// address 0 and one for each function found by the control-flow graph.
+//
+// Code is structured by address order: a jump backwards to a block starts a
+// loop, and a conditional jump forwards becomes an if, or an if/else when
+// the skipped code ends by jumping past the code jumped to. Anything else
+// is shown as a goto.
+//
+// Function calls use the relative base as a stack pointer, so within a
+// function relative addresses are named by their offset from the base on
+// entry, which holds the return address: `local_1` is one word above it and
+// `caller_1` one word below. In main the base starts at 0, so they're shown
+// as plain memory addresses. Adjustments to the base are left out wherever
+// they are tracked this way.
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+struct Loop {
+    header: usize,
+    exit: Option<usize>,
+}
+
+struct Function<'a> {
+    cfg: &'a ControlFlowGraph,
+    entry: usize,
+    // Starts of the function's blocks, in address order
+    blocks: Vec<usize>,
+    index: BTreeMap<usize, usize>,
+    // Base on entry to each block, relative to the base on entry to the
+    // function. None where it can't be worked out statically.
+    deltas: BTreeMap<usize, Option<i64>>,
+}
+
+#[derive(Default)]
+struct Output {
+    lines: Vec<(usize, String)>,
+    // First line of each block, to put labels on
+    block_lines: Vec<(usize, usize)>,
+    labels: BTreeSet<usize>,
+}
+
+impl Output {
+    fn line(&mut self, indent: usize, text: String) {
+        self.lines.push((indent, text));
+    }
+}
+
+fn name(address: usize) -> String {
+    if address == 0 {
+        "main".to_string()
+    } else {
+        format!("func_{}", address)
+    }
+}
+
+fn arb_amount(ins: &DecodedInstruction) -> Option<i64> {
+    match (ins.opcode, ins.operands[0]) {
+        (Opcode::AdjustBase, Operand::Immediate(n)) => Some(n),
+        _ => None,
+    }
+}
+
+fn negate(condition: &str) -> String {
+    if let Some(var) = condition.strip_suffix(" != 0") {
+        format!("{} == 0", var)
+    } else if let Some(var) = condition.strip_suffix(" == 0") {
+        format!("{} != 0", var)
+    } else {
+        format!("!({})", condition)
+    }
+}
+
+impl<'a> Function<'a> {
+    fn new(cfg: &'a ControlFlowGraph, entry: usize, blocks: Vec<usize>) -> Self {
+        let index = blocks.iter().enumerate().map(|(i, &b)| (b, i)).collect();
+        let mut function = Function {
+            cfg,
+            entry,
+            blocks,
+            index,
+            deltas: BTreeMap::new(),
+        };
+        function.find_deltas();
+        function
+    }
+
+    fn block(&self, start: usize) -> &BasicBlock {
+        &self.cfg.blocks[&start]
+    }
+
+    fn find_deltas(&mut self) {
+        let mut to_visit = VecDeque::from([(self.entry, Some(0))]);
+        while let Some((start, delta)) = to_visit.pop_front() {
+            match self.deltas.get(&start) {
+                Some(known) if *known == delta || known.is_none() => continue,
+                Some(_) => {
+                    self.deltas.insert(start, None);
+                }
+                None => {
+                    self.deltas.insert(start, delta);
+                }
+            }
+            let delta = self.deltas[&start];
+            let out =
+                self.block(start)
+                    .instructions
+                    .iter()
+                    .fold(delta, |d, ins| match (ins.opcode, d) {
+                        (Opcode::AdjustBase, Some(d)) => arb_amount(ins).map(|n| d + n),
+                        (_, d) => d,
+                    });
+            for edge in self.cfg.successors(start) {
+                if edge.kind != EdgeKind::Call && self.index.contains_key(&edge.to) {
+                    to_visit.push_back((edge.to, out));
+                }
+            }
+        }
+    }
+
+    fn operand(&self, operand: Operand, delta: Option<i64>) -> String {
+        match (operand, delta) {
+            (Operand::Immediate(v), _) => v.to_string(),
+            (Operand::Position(p), _) => format!("mem[{}]", p),
+            (Operand::Relative(k), Some(d)) if self.entry == 0 => format!("mem[{}]", d + k),
+            (Operand::Relative(k), Some(d)) if d + k >= 0 => format!("local_{}", d + k),
+            (Operand::Relative(k), Some(d)) => format!("caller_{}", -(d + k)),
+            (Operand::Relative(k), None) => format!("rel[{}]", k),
+        }
+    }
+
+    fn statement(&self, ins: &DecodedInstruction, delta: &mut Option<i64>) -> Option<String> {
+        let op = |idx: usize| self.operand(ins.operands[idx], *delta);
+        let imm = |idx: usize| match ins.operands[idx] {
+            Operand::Immediate(v) => Some(v),
+            _ => None,
+        };
+        Some(match ins.opcode {
+            Opcode::Add => match (imm(0), imm(1)) {
+                (Some(0), _) => format!("{} = {};", op(2), op(1)),
+                (_, Some(0)) => format!("{} = {};", op(2), op(0)),
+                (_, Some(n)) if n < 0 => format!("{} = {} - {};", op(2), op(0), -n),
+                _ => format!("{} = {} + {};", op(2), op(0), op(1)),
+            },
+            Opcode::Mul => match (imm(0), imm(1)) {
+                (Some(1), _) => format!("{} = {};", op(2), op(1)),
+                (_, Some(1)) => format!("{} = {};", op(2), op(0)),
+                (_, Some(-1)) => format!("{} = -{};", op(2), op(0)),
+                _ => format!("{} = {} * {};", op(2), op(0), op(1)),
+            },
+            Opcode::LessThan => format!("{} = {} < {};", op(2), op(0), op(1)),
+            Opcode::Equals => format!("{} = {} == {};", op(2), op(0), op(1)),
+            Opcode::In => format!("{} = input();", op(0)),
+            Opcode::Out => format!("output({});", op(0)),
+            Opcode::AdjustBase => match (arb_amount(ins), *delta) {
+                (Some(n), Some(d)) => {
+                    *delta = Some(d + n);
+                    return None;
+                }
+                (n, _) => {
+                    let text = format!("base += {};", op(0));
+                    *delta = n.and(*delta);
+                    text
+                }
+            },
+            Opcode::Halt => "halt();".to_string(),
+            Opcode::Custom(_) => {
+                let args = (0..ins.operands.len()).map(op).collect::<Vec<_>>();
+                format!("{}({});", ins.opcode, args.join(", "))
+            }
+            Opcode::JumpIfTrue | Opcode::JumpIfFalse => return None,
+        })
+    }
+
+    // Condition under which a conditional jump is taken
+    fn condition(&self, ins: &DecodedInstruction, delta: Option<i64>) -> String {
+        let value = self.operand(ins.operands[0], delta);
+        match ins.opcode {
+            Opcode::JumpIfTrue => format!("{} != 0", value),
+            _ => format!("{} == 0", value),
+        }
+    }
+
+    // Whether control can reach `address` by leaving the block at index `i`
+    // without leaving the region ending at `hi`, i.e. by structured code
+    fn in_region(&self, address: usize, i: usize, hi: usize, follow: Option<usize>) -> bool {
+        match self.index.get(&address) {
+            Some(&idx) => (idx > i && idx < hi) || (idx == hi && follow == Some(address)),
+            None => false,
+        }
+    }
+
+    // Statement to move from the block at index i to target, if one is needed
+    fn transfer(
+        &self,
+        out: &mut Output,
+        target: usize,
+        i: usize,
+        is_last: bool,
+        ctx: Option<Loop>,
+        follow: Option<usize>,
+    ) -> Option<String> {
+        if is_last && follow == Some(target) {
+            None
+        } else if ctx.is_some_and(|l| l.header == target) {
+            Some("continue;".to_string())
+        } else if ctx.is_some_and(|l| l.exit == Some(target)) {
+            Some("break;".to_string())
+        } else if !is_last && self.blocks.get(i + 1) == Some(&target) {
+            None
+        } else {
+            out.labels.insert(target);
+            Some(format!("goto L_{};", target))
+        }
+    }
+
+    fn back_edge_from(&self, i: usize, hi: usize) -> Option<usize> {
+        let header = self.blocks[i];
+        self.cfg
+            .predecessors(header)
+            .filter(|edge| edge.kind == EdgeKind::Jump)
+            .filter_map(|edge| self.index.get(&edge.from).copied())
+            .filter(|&idx| idx >= i && idx < hi)
+            .max()
+    }
+
+    fn emit(
+        &self,
+        out: &mut Output,
+        (lo, hi): (usize, usize),
+        ctx: Option<Loop>,
+        follow: Option<usize>,
+        indent: usize,
+    ) {
+        let mut i = lo;
+        while i < hi {
+            let start = self.blocks[i];
+            if ctx.is_none_or(|l| l.header != start || i != lo) {
+                if let Some(last) = self.back_edge_from(i, hi) {
+                    let inner = Loop {
+                        header: start,
+                        exit: self.blocks.get(last + 1).copied(),
+                    };
+                    out.line(indent, "loop {".to_string());
+                    self.emit(out, (i, last + 1), Some(inner), Some(start), indent + 1);
+                    out.line(indent, "}".to_string());
+                    i = last + 1;
+                    continue;
+                }
+            }
+
+            out.block_lines.push((out.lines.len(), start));
+            let block = self.block(start);
+            let is_last = i == hi - 1;
+            let mut delta = self.deltas.get(&start).copied().flatten();
+            let last = block.last();
+            let call = self
+                .cfg
+                .successors(start)
+                .find(|edge| edge.kind == EdgeKind::Call)
+                .map(|edge| edge.to);
+            let body = if call.is_some() {
+                &block.instructions[..block.instructions.len().saturating_sub(2)]
+            } else {
+                &block.instructions[..]
+            };
+            for ins in body {
+                if let Some(text) = self.statement(ins, &mut delta) {
+                    out.line(indent, text);
+                }
+            }
+
+            if let Some(target) = call {
+                out.line(indent, format!("{}();", name(target)));
+                let return_site = self
+                    .cfg
+                    .successors(start)
+                    .find(|edge| edge.kind == EdgeKind::CallReturn)
+                    .map(|edge| edge.to);
+                if let Some(text) =
+                    return_site.and_then(|r| self.transfer(out, r, i, is_last, ctx, follow))
+                {
+                    out.line(indent, text);
+                }
+            } else if block.returns {
+                out.line(indent, "return;".to_string());
+            } else if block.indirect_jump {
+                let target = self.operand(last.operands[1], delta);
+                if last.can_fall_through() {
+                    let condition = self.condition(last, delta);
+                    out.line(indent, format!("if ({}) goto *{};", condition, target));
+                } else {
+                    out.line(indent, format!("goto *{};", target));
+                }
+            } else if last.opcode == Opcode::Halt {
+            } else if !last.can_jump() {
+                let next = block.end();
+                if let Some(text) = self.transfer(out, next, i, is_last, ctx, follow) {
+                    out.line(indent, text);
+                }
+            } else if !last.can_fall_through() {
+                let target = last.jump_target().unwrap_or(0) as usize;
+                if let Some(text) = self.transfer(out, target, i, is_last, ctx, follow) {
+                    out.line(indent, text);
+                }
+            } else {
+                let target = last.jump_target().unwrap_or(0) as usize;
+                let condition = self.condition(last, delta);
+                let next = block.end();
+                if self.in_region(target, i, hi, follow) && self.index[&target] > i + 1 {
+                    let t = self.index[&target];
+                    let skip = self.block(self.blocks[t - 1]).last();
+                    let join = skip
+                        .jump_target()
+                        .filter(|_| skip.can_jump() && !skip.can_fall_through())
+                        .map(|e| e as usize)
+                        .filter(|&e| self.in_region(e, t, hi, follow));
+                    out.line(indent, format!("if ({}) {{", negate(&condition)));
+                    match join {
+                        Some(join)
+                            if self
+                                .cfg
+                                .successors(self.blocks[t - 1])
+                                .all(|e| e.kind != EdgeKind::Call) =>
+                        {
+                            self.emit(out, (i + 1, t), ctx, Some(join), indent + 1);
+                            out.line(indent, "} else {".to_string());
+                            let end = self.index.get(&join).copied().unwrap_or(hi);
+                            self.emit(out, (t, end), ctx, Some(join), indent + 1);
+                            out.line(indent, "}".to_string());
+                            i = end;
+                        }
+                        _ => {
+                            self.emit(out, (i + 1, t), ctx, Some(target), indent + 1);
+                            out.line(indent, "}".to_string());
+                            i = t;
+                        }
+                    }
+                    continue;
+                }
+
+                let taken = self.transfer(out, target, i, is_last, ctx, follow);
+                let fall = self.transfer(out, next, i, is_last, ctx, follow);
+                match (taken, fall) {
+                    (None, None) => {}
+                    (None, Some(fall)) => {
+                        out.line(indent, format!("if ({}) {{", negate(&condition)));
+                        out.line(indent + 1, fall);
+                        out.line(indent, "}".to_string());
+                    }
+                    (Some(taken), fall) => {
+                        out.line(indent, format!("if ({}) {{", condition));
+                        out.line(indent + 1, taken);
+                        out.line(indent, "}".to_string());
+                        if let Some(fall) = fall {
+                            out.line(indent, fall);
+                        }
+                    }
+                }
+            }
+            i += 1;
+        }
+    }
+
+    fn write(&self, text: &mut String) {
+        let mut out = Output::default();
+        self.emit(&mut out, (0, self.blocks.len()), None, None, 1);
+
+        let labels = out
+            .block_lines
+            .iter()
+            .filter(|(_, start)| out.labels.contains(start))
+            .copied()
+            .collect::<BTreeMap<_, _>>();
+        writeln!(text, "fn {}() {{", name(self.entry)).unwrap();
+        for (idx, (indent, line)) in out.lines.iter().enumerate() {
+            if let Some(start) = labels.get(&idx) {
+                writeln!(text, "L_{}:", start).unwrap();
+            }
+            writeln!(text, "{}{}", "    ".repeat(*indent), line).unwrap();
+        }
+        writeln!(text, "}}").unwrap();
+    }
+}
+
+// Assigns each block to the first function, in address order, which can
+// reach it without making a call
+fn partition(cfg: &ControlFlowGraph) -> BTreeMap<usize, Vec<usize>> {
+    let mut owner: BTreeMap<usize, usize> = BTreeMap::new();
+    let entries = Some(0)
+        .into_iter()
+        .chain(cfg.functions.iter().copied())
+        .filter(|entry| cfg.blocks.contains_key(entry))
+        .collect::<BTreeSet<_>>();
+    for &entry in entries.iter() {
+        let mut to_visit = vec![entry];
+        while let Some(start) = to_visit.pop() {
+            if owner.contains_key(&start) || (start != entry && entries.contains(&start)) {
+                continue;
+            }
+            owner.insert(start, entry);
+            to_visit.extend(
+                cfg.successors(start)
+                    .filter(|edge| edge.kind != EdgeKind::Call)
+                    .map(|edge| edge.to)
+                    .filter(|to| cfg.blocks.contains_key(to)),
+            );
+        }
+    }
+
+    let mut functions: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (start, entry) in owner {
+        functions.entry(entry).or_default().push(start);
+    }
+    functions
+}
+
+pub fn decompile(program: &[i64]) -> String {
+    let cfg = ControlFlowGraph::build(program);
+    let mut text = String::new();
+    for (idx, (entry, blocks)) in partition(&cfg).into_iter().enumerate() {
+        if idx > 0 {
+            text.push('\n');
+        }
+        Function::new(&cfg, entry, blocks).write(&mut text);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::*;
+
+    #[test]
+    fn test_loop_if_else_and_call() {
+        let program = assemble(
+            "
+                    arb #stack
+            loop:   in x
+                    jf x, #done
+                    lt x, #10, small
+                    jf small, #big
+                    out #1
+                    jt #1, #next
+            big:    add x, #0, @1
+                    add #next, #0, @0
+                    jt #1, #twice
+            next:   jt #1, #loop
+            done:   hlt
+            twice:  arb #2
+                    mul @-1, #2, @-1
+                    out @-1
+                    arb #-2
+                    jf #0, @0
+            x:      data 0
+            small:  data 0
+            stack:  zeros 8
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            decompile(&program),
+            "\
+fn main() {
+    loop {
+        mem[47] = input();
+        if (mem[47] == 0) {
+            break;
+        }
+        mem[48] = mem[47] < 10;
+        if (mem[48] != 0) {
+            output(1);
+        } else {
+            mem[50] = mem[47];
+            func_34();
+        }
+    }
+    halt();
+}
+
+fn func_34() {
+    local_1 = local_1 * 2;
+    output(local_1);
+    return;
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_goto_fallback() {
+        // Jumps into the middle of a loop from before it
+        let program = assemble(
+            "
+                    jt #1, #middle
+            top:    out #1
+            middle: out #2
+                    jt #1, #top
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            decompile(&program),
+            "\
+fn main() {
+    goto L_5;
+    loop {
+        output(1);
+L_5:
+        output(2);
+    }
+}
+"
+        );
+    }
+}
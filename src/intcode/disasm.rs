@@ -0,0 +1,478 @@
+use super::cfg::call_from;
+use super::custom::{CustomOpcodes, OperandKind};
+use super::{Instruction, IntCodeState};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
+pub enum Opcode {
+    Add,
+    Mul,
+    In,
+    Out,
+    JumpIfTrue,
+    JumpIfFalse,
+    LessThan,
+    Equals,
+    AdjustBase,
+    Halt,
+    // Registered with IntCodeState::register_opcode, so only found when
+    // decoding a live program which knows about it
+    Custom(u32),
+}
+
+pub const OPCODES: [Opcode; 10] = [
+    Opcode::Add,
+    Opcode::Mul,
+    Opcode::In,
+    Opcode::Out,
+    Opcode::JumpIfTrue,
+    Opcode::JumpIfFalse,
+    Opcode::LessThan,
+    Opcode::Equals,
+    Opcode::AdjustBase,
+    Opcode::Halt,
+];
+
+impl Opcode {
+    pub fn from_code(code: u32) -> Option<Opcode> {
+        OPCODES.iter().find(|op| op.code() == code).copied()
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+        OPCODES.iter().find(|op| op.mnemonic() == mnemonic).copied()
+    }
+
+    pub fn code(&self) -> u32 {
+        match self {
+            Opcode::Add => 1,
+            Opcode::Mul => 2,
+            Opcode::In => 3,
+            Opcode::Out => 4,
+            Opcode::JumpIfTrue => 5,
+            Opcode::JumpIfFalse => 6,
+            Opcode::LessThan => 7,
+            Opcode::Equals => 8,
+            Opcode::AdjustBase => 9,
+            Opcode::Halt => 99,
+            Opcode::Custom(code) => *code,
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Opcode::Add => "add",
+            Opcode::Mul => "mul",
+            Opcode::In => "in",
+            Opcode::Out => "out",
+            Opcode::JumpIfTrue => "jt",
+            Opcode::JumpIfFalse => "jf",
+            Opcode::LessThan => "lt",
+            Opcode::Equals => "eq",
+            Opcode::AdjustBase => "arb",
+            Opcode::Halt => "hlt",
+            Opcode::Custom(_) => "op",
+        }
+    }
+
+    // Custom opcodes take whatever operands they were registered with, which
+    // decoded instructions list, so they are counted as none here
+    pub fn arity(&self) -> usize {
+        match self {
+            Opcode::Add | Opcode::Mul | Opcode::LessThan | Opcode::Equals => 3,
+            Opcode::JumpIfTrue | Opcode::JumpIfFalse => 2,
+            Opcode::In | Opcode::Out | Opcode::AdjustBase => 1,
+            Opcode::Halt | Opcode::Custom(_) => 0,
+        }
+    }
+
+    // Index of the operand this opcode writes to, if any
+    pub fn write_operand(&self) -> Option<usize> {
+        match self {
+            Opcode::Add | Opcode::Mul | Opcode::LessThan | Opcode::Equals => Some(2),
+            Opcode::In => Some(0),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Opcode::Custom(code) => write!(f, "op{}", code),
+            opcode => f.write_str(opcode.mnemonic()),
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
+pub enum Operand {
+    Position(i64),
+    Immediate(i64),
+    Relative(i64),
+}
+
+impl Operand {
+    fn from_mode(mode: u32, value: i64) -> Option<Operand> {
+        match mode {
+            0 => Some(Operand::Position(value)),
+            1 => Some(Operand::Immediate(value)),
+            2 => Some(Operand::Relative(value)),
+            _ => None,
+        }
+    }
+
+    pub fn mode(&self) -> i64 {
+        match self {
+            Operand::Position(_) => 0,
+            Operand::Immediate(_) => 1,
+            Operand::Relative(_) => 2,
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        match *self {
+            Operand::Position(v) | Operand::Immediate(v) | Operand::Relative(v) => v,
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Position(v) => write!(f, "{}", v),
+            Operand::Immediate(v) => write!(f, "#{}", v),
+            Operand::Relative(v) => write!(f, "@{}", v),
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Hash)]
+pub struct DecodedInstruction {
+    pub address: usize,
+    pub opcode: Opcode,
+    pub operands: Vec<Operand>,
+}
+
+impl DecodedInstruction {
+    // Number of words the instruction occupies
+    pub fn size(&self) -> usize {
+        1 + self.operands.len()
+    }
+
+    pub fn encode(&self) -> Vec<i64> {
+        let mut raw = self.opcode.code() as i64;
+        let mut scale = 100;
+        for operand in self.operands.iter() {
+            raw += operand.mode() * scale;
+            scale *= 10;
+        }
+        Some(raw)
+            .into_iter()
+            .chain(self.operands.iter().map(|op| op.value()))
+            .collect()
+    }
+
+    // Statically known jump target, if this is a jump with an immediate target
+    pub fn jump_target(&self) -> Option<i64> {
+        match (self.opcode, self.operands.get(1)) {
+            (Opcode::JumpIfTrue | Opcode::JumpIfFalse, Some(Operand::Immediate(target))) => {
+                Some(*target)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn can_jump(&self) -> bool {
+        match (self.opcode, self.operands.first()) {
+            (Opcode::JumpIfTrue, Some(Operand::Immediate(cond))) => *cond != 0,
+            (Opcode::JumpIfFalse, Some(Operand::Immediate(cond))) => *cond == 0,
+            (Opcode::JumpIfTrue | Opcode::JumpIfFalse, _) => true,
+            _ => false,
+        }
+    }
+
+    pub fn can_fall_through(&self) -> bool {
+        match (self.opcode, self.operands.first()) {
+            (Opcode::Halt, _) => false,
+            (Opcode::JumpIfTrue, Some(Operand::Immediate(cond))) => *cond == 0,
+            (Opcode::JumpIfFalse, Some(Operand::Immediate(cond))) => *cond != 0,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for DecodedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.opcode)?;
+        for (idx, operand) in self.operands.iter().enumerate() {
+            write!(f, "{}{}", if idx == 0 { " " } else { ", " }, operand)?;
+        }
+        Ok(())
+    }
+}
+
+// Decodes the instruction at address, if the words there form a canonical
+// instruction: known opcode, no unused mode digits, no immediate mode writes
+// and all operands inside the program.
+pub fn decode_instruction(program: &[i64], address: usize) -> Option<DecodedInstruction> {
+    decode(program, address, true, &CustomOpcodes::default())
+}
+
+// Non-canonical decoding accepts anything the VM would execute
+fn decode(
+    program: &[i64],
+    address: usize,
+    canonical: bool,
+    custom: &CustomOpcodes,
+) -> Option<DecodedInstruction> {
+    let raw = *program.get(address)?;
+    let instruction = Instruction::try_from(raw).ok()?;
+    // Whether each operand is written to
+    let (opcode, writes) = match Opcode::from_code(instruction.typ()) {
+        Some(opcode) => (
+            opcode,
+            (0..opcode.arity())
+                .map(|idx| opcode.write_operand() == Some(idx))
+                .collect::<Vec<_>>(),
+        ),
+        None => {
+            let custom = custom.get(instruction.typ())?;
+            (
+                Opcode::Custom(custom.code),
+                custom
+                    .operands
+                    .iter()
+                    .map(|&kind| kind == OperandKind::Write)
+                    .collect(),
+            )
+        }
+    };
+
+    let unused_modes = 10u32
+        .checked_pow(writes.len() as u32 + 2)
+        .is_some_and(|place| instruction.num / place != 0);
+    if canonical && unused_modes {
+        return None;
+    }
+
+    let operands = (0..writes.len())
+        .map(|idx| Operand::from_mode(instruction.mode(idx), *program.get(address + 1 + idx)?))
+        .collect::<Option<Vec<_>>>()?;
+
+    let immediate_write = writes
+        .iter()
+        .zip(operands.iter())
+        .any(|(&write, operand)| write && matches!(operand, Operand::Immediate(_)));
+    if immediate_write {
+        return None;
+    }
+
+    Some(DecodedInstruction {
+        address,
+        opcode,
+        operands,
+    })
+}
+
+impl IntCodeState {
+    // Registered custom opcodes are decoded too
+    fn decode_live(&self, address: i64, canonical: bool) -> Option<DecodedInstruction> {
+        let size = 4.max(1 + self.custom_opcodes.max_operands()) as i64;
+        let words = (address..address + size)
+            .map_while(|a| self.try_get_mem(a).ok())
+            .collect::<Vec<_>>();
+        let mut ins = decode(&words, 0, canonical, &self.custom_opcodes)?;
+        ins.address = address as usize;
+        Some(ins)
+    }
+
+    // Decodes the instruction at an address of a live program
+    pub fn decode_at(&self, address: i64) -> Option<DecodedInstruction> {
+        self.decode_live(address, true)
+    }
+
+    // Also decodes instructions the VM would execute despite not being
+    // canonical, such as ones with a mode digit for an operand they lack
+    pub fn decode_executable_at(&self, address: i64) -> Option<DecodedInstruction> {
+        self.decode_live(address, false)
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum Line {
+    Code(DecodedInstruction),
+    Data { address: usize, value: i64 },
+}
+
+impl Line {
+    pub fn address(&self) -> usize {
+        match self {
+            Line::Code(ins) => ins.address,
+            Line::Data { address, .. } => *address,
+        }
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Code(ins) => write!(f, "{:>5}: {}", ins.address, ins),
+            Line::Data { address, value } => write!(f, "{:>5}: data {}", address, value),
+        }
+    }
+}
+
+// Decodes everything reachable from address 0 by following fall-through,
+// immediate jump targets and where calls return to. Words which are never
+// reached are shown as data.
+pub fn disassemble(program: &[i64]) -> Vec<Line> {
+    let mut covered = vec![false; program.len()];
+    let mut code: BTreeMap<usize, DecodedInstruction> = BTreeMap::new();
+    let mut to_visit = vec![0];
+
+    while let Some(address) = to_visit.pop() {
+        let Some(ins) = decode_instruction(program, address) else {
+            continue;
+        };
+        if covered[address..address + ins.size()].iter().any(|&c| c) {
+            continue;
+        }
+        covered[address..address + ins.size()].fill(true);
+
+        if ins.can_fall_through() {
+            let next = address + ins.size();
+            to_visit.push(next);
+            if let Some(call) =
+                decode_instruction(program, next).and_then(|jump| call_from(program, &ins, &jump))
+            {
+                to_visit.push(call.return_site);
+            }
+        }
+        if ins.can_jump() {
+            if let Some(target) = ins.jump_target() {
+                if let Ok(target) = usize::try_from(target) {
+                    to_visit.push(target);
+                }
+            }
+        }
+        code.insert(address, ins);
+    }
+
+    let mut lines = vec![];
+    let mut address = 0;
+    while address < program.len() {
+        if let Some(ins) = code.remove(&address) {
+            address += ins.size();
+            lines.push(Line::Code(ins));
+        } else {
+            lines.push(Line::Data {
+                address,
+                value: program[address],
+            });
+            address += 1;
+        }
+    }
+    lines
+}
+
+pub fn disassemble_to_string(program: &[i64]) -> String {
+    disassemble(program)
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_modes() {
+        let ins = decode_instruction(&[21101, 4, -3, 7], 0).unwrap();
+        assert_eq!(ins.opcode, Opcode::Add);
+        assert_eq!(
+            ins.operands,
+            vec![
+                Operand::Immediate(4),
+                Operand::Immediate(-3),
+                Operand::Relative(7)
+            ]
+        );
+        assert_eq!(ins.to_string(), "add #4, #-3, @7");
+        assert_eq!(ins.encode(), vec![21101, 4, -3, 7]);
+    }
+
+    #[test]
+    fn test_decode_rejects_non_canonical() {
+        assert_eq!(decode_instruction(&[11101, 1, 2, 3], 0), None);
+        assert_eq!(decode_instruction(&[1104, 1, 2], 0), None);
+        assert_eq!(decode_instruction(&[199], 0), None);
+        assert_eq!(decode_instruction(&[1, 0, 0], 0), None);
+        assert_eq!(decode_instruction(&[42], 0), None);
+    }
+
+    #[test]
+    fn test_disassemble_listing() {
+        let program = vec![3, 9, 8, 9, 10, 9, 4, 9, 99, -1, 8];
+        assert_eq!(
+            disassemble_to_string(&program),
+            "    0: in 9
+    2: eq 9, 10, 9
+    6: out 9
+    8: hlt
+    9: data -1
+   10: data 8
+"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_follows_jumps() {
+        // Jumps over a word which would otherwise decode as an instruction
+        let program = vec![1105, 1, 4, 1, 104, 0, 99];
+        let lines = disassemble(&program);
+        assert_eq!(
+            lines[1],
+            Line::Data {
+                address: 3,
+                value: 1
+            }
+        );
+        assert_eq!(lines[2].to_string(), "    4: out #0");
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn test_disassemble_follows_calls() {
+        // arb #100; call 13, returning to 9; out #42; hlt; 13: return
+        let program = vec![
+            109, 100, 21101, 9, 0, 0, 1106, 0, 13, 104, 42, 99, 0, 2105, 1, 0,
+        ];
+        let listing = disassemble(&program)
+            .iter()
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listing,
+            vec![
+                "    0: arb #100",
+                "    2: add #9, #0, @0",
+                "    6: jf #0, #13",
+                "    9: out #42",
+                "   11: hlt",
+                "   12: data 0",
+                "   13: jt #1, @0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_live_state() {
+        let mut prog: IntCodeState = vec![1101, 40, 2, 0, 99].into();
+        assert_eq!(prog.decode_at(0).unwrap().to_string(), "add #40, #2, 0");
+        prog.execute_until_halt_no_input();
+        assert_eq!(prog.decode_at(0), None);
+        assert_eq!(prog.decode_at(4).unwrap().opcode, Opcode::Halt);
+    }
+}
@@ -0,0 +1,358 @@
+use super::{IntCodeError, IntCodeState, Step};
+use std::fmt;
+
+// Instructions are decoded once into a one-byte code per address, which
+// indexes a table of handlers specialised for that opcode and combination
+// of parameter modes. Code 0 means not decoded yet. Any write to an address
+// clears its code, so self-modifying programs see their changes.
+
+type InputHandler<'a> = dyn FnMut(&mut IntCodeState) -> Option<i64> + 'a;
+
+type Handler = fn(&mut IntCodeState, &mut InputHandler) -> Result<Step, IntCodeError>;
+
+const UNDECODED: u8 = 0;
+const ADD: u8 = 1;
+const MUL: u8 = ADD + 18;
+const LESS_THAN: u8 = MUL + 18;
+const EQUALS: u8 = LESS_THAN + 18;
+const JUMP_IF_TRUE: u8 = EQUALS + 18;
+const JUMP_IF_FALSE: u8 = JUMP_IF_TRUE + 9;
+const OUTPUT: u8 = JUMP_IF_FALSE + 9;
+const ADJUST_BASE: u8 = OUTPUT + 3;
+const INPUT: u8 = ADJUST_BASE + 3;
+const HALT: u8 = INPUT + 2;
+
+// Codes for each address of the program as loaded. Code beyond the
+// program's original length is decoded every time it runs.
+#[derive(Clone, Default)]
+pub(super) struct HandlerCache(Vec<u8>);
+
+impl HandlerCache {
+    pub fn new(len: usize) -> Self {
+        HandlerCache(vec![UNDECODED; len])
+    }
+
+    #[inline]
+    fn get(&self, address: i64) -> u8 {
+        self.0.get(address as usize).copied().unwrap_or(UNDECODED)
+    }
+
+    #[inline]
+    pub fn invalidate(&mut self, address: i64) {
+        if let Some(code) = self.0.get_mut(address as usize) {
+            *code = UNDECODED;
+        }
+    }
+}
+
+impl PartialEq for HandlerCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for HandlerCache {}
+
+impl fmt::Debug for HandlerCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HandlerCache")
+    }
+}
+
+// None for anything which isn't a valid instruction, which is left to the
+// generic path to report.
+fn code_for(raw: i64) -> Option<u8> {
+    let num = u32::try_from(raw).ok()?;
+    let (m1, m2, m3) = ((num / 100) % 10, (num / 1000) % 10, (num / 10000) % 10);
+    let read = |mode: u32| (mode <= 2).then_some(mode as u8);
+    let write = |mode: u32| match mode {
+        0 => Some(0),
+        2 => Some(1),
+        _ => None,
+    };
+    let three = |base: u8| Some(base + read(m1)? * 6 + read(m2)? * 2 + write(m3)?);
+    let two = |base: u8| Some(base + read(m1)? * 3 + read(m2)?);
+    match num % 100 {
+        1 => three(ADD),
+        2 => three(MUL),
+        3 => Some(INPUT + write(m1)?),
+        4 => Some(OUTPUT + read(m1)?),
+        5 => two(JUMP_IF_TRUE),
+        6 => two(JUMP_IF_FALSE),
+        7 => three(LESS_THAN),
+        8 => three(EQUALS),
+        9 => Some(ADJUST_BASE + read(m1)?),
+        99 => Some(HALT),
+        _ => None,
+    }
+}
+
+impl IntCodeState {
+    #[inline(always)]
+    fn param<const MODE: u8>(&mut self, offset: i64) -> Result<i64, IntCodeError> {
+        let pos = self.load(self.offset(self.instruction_ptr, offset)?)?;
+        match MODE {
+            0 => self.load(pos),
+            1 => Ok(pos),
+            _ => self.load(self.offset(self.base_ptr, pos)?),
+        }
+    }
+
+    #[inline(always)]
+    fn param_address<const MODE: u8>(&mut self, offset: i64) -> Result<i64, IntCodeError> {
+        let pos = self.load(self.offset(self.instruction_ptr, offset)?)?;
+        let address = if MODE == 0 {
+            pos
+        } else {
+            self.offset(self.base_ptr, pos)?
+        };
+        self.check_address(address)?;
+        Ok(address)
+    }
+
+    // Decodes every word of the program which looks like an instruction, so
+    // that clones of this state start with nothing left to decode. Worth it
+    // when running many short-lived copies of one program.
+    pub fn predecode(&mut self) {
+        for address in 0..self.handlers.0.len() {
+            let raw = self.get_mem(address as i64);
+            self.handlers.0[address] = code_for(raw).unwrap_or(UNDECODED);
+        }
+    }
+
+    // Decodes every instruction each time it runs, as if there were no
+    // cache. Only useful for measuring what the cache is worth.
+    pub fn disable_decode_cache(&mut self) {
+        self.handlers = HandlerCache::new(0);
+    }
+
+    #[inline]
+    pub(super) fn step_cached(
+        &mut self,
+        input_handler: &mut InputHandler,
+    ) -> Result<Step, IntCodeError> {
+        let code = self.handlers.get(self.instruction_ptr);
+        HANDLERS[code as usize](self, input_handler)
+    }
+}
+
+fn decode_and_run(
+    prog: &mut IntCodeState,
+    input_handler: &mut InputHandler,
+) -> Result<Step, IntCodeError> {
+    let code = match prog
+        .try_get_mem(prog.instruction_ptr)
+        .ok()
+        .and_then(code_for)
+    {
+        Some(code) => code,
+        None => return prog.step_generic(input_handler),
+    };
+    if let Some(cached) = prog.handlers.0.get_mut(prog.instruction_ptr as usize) {
+        *cached = code;
+    }
+    HANDLERS[code as usize](prog, input_handler)
+}
+
+fn binary<const M1: u8, const M2: u8, const M3: u8>(
+    prog: &mut IntCodeState,
+    op: impl FnOnce(i64, i64) -> i64,
+) -> Result<Step, IntCodeError> {
+    let x = prog.param::<M1>(1)?;
+    let y = prog.param::<M2>(2)?;
+    let dest = prog.param_address::<M3>(3)?;
+    prog.store(dest, op(x, y));
+    prog.instruction_ptr += 4;
+    Ok(Step::Continue)
+}
+
+fn add<const M1: u8, const M2: u8, const M3: u8>(
+    prog: &mut IntCodeState,
+    _: &mut InputHandler,
+) -> Result<Step, IntCodeError> {
+    binary::<M1, M2, M3>(prog, |x, y| x + y)
+}
+
+fn mul<const M1: u8, const M2: u8, const M3: u8>(
+    prog: &mut IntCodeState,
+    _: &mut InputHandler,
+) -> Result<Step, IntCodeError> {
+    binary::<M1, M2, M3>(prog, |x, y| x * y)
+}
+
+fn less_than<const M1: u8, const M2: u8, const M3: u8>(
+    prog: &mut IntCodeState,
+    _: &mut InputHandler,
+) -> Result<Step, IntCodeError> {
+    binary::<M1, M2, M3>(prog, |x, y| (x < y) as i64)
+}
+
+fn equals<const M1: u8, const M2: u8, const M3: u8>(
+    prog: &mut IntCodeState,
+    _: &mut InputHandler,
+) -> Result<Step, IntCodeError> {
+    binary::<M1, M2, M3>(prog, |x, y| (x == y) as i64)
+}
+
+fn jump_if<const COND: bool, const M1: u8, const M2: u8>(
+    prog: &mut IntCodeState,
+    _: &mut InputHandler,
+) -> Result<Step, IntCodeError> {
+    if (prog.param::<M1>(1)? != 0) == COND {
+        prog.instruction_ptr = prog.param::<M2>(2)?;
+    } else {
+        prog.instruction_ptr += 3;
+    }
+    Ok(Step::Continue)
+}
+
+fn output<const M1: u8>(
+    prog: &mut IntCodeState,
+    _: &mut InputHandler,
+) -> Result<Step, IntCodeError> {
+    let x = prog.param::<M1>(1)?;
+    prog.out_buffer.push_back(x);
+    prog.instruction_ptr += 2;
+    Ok(Step::Output)
+}
+
+fn adjust_base<const M1: u8>(
+    prog: &mut IntCodeState,
+    _: &mut InputHandler,
+) -> Result<Step, IntCodeError> {
+    let adjustment = prog.param::<M1>(1)?;
+    prog.base_ptr = prog.offset(prog.base_ptr, adjustment)?;
+    prog.instruction_ptr += 2;
+    Ok(Step::Continue)
+}
+
+fn input<const M1: u8>(
+    prog: &mut IntCodeState,
+    input_handler: &mut InputHandler,
+) -> Result<Step, IntCodeError> {
+    let dest = prog.param_address::<M1>(1)?;
+    if let Some(inp) = input_handler(prog) {
+        prog.store(dest, inp);
+        prog.instruction_ptr += 2;
+        Ok(Step::Continue)
+    } else {
+        Ok(Step::Blocked)
+    }
+}
+
+fn halt(_: &mut IntCodeState, _: &mut InputHandler) -> Result<Step, IntCodeError> {
+    Ok(Step::Halted)
+}
+
+// Fills in handlers for each read mode of the first parameter
+macro_rules! one_param {
+    ($table:ident, $base:expr, $handler:ident) => {
+        $table[$base as usize] = $handler::<0>;
+        $table[$base as usize + 1] = $handler::<1>;
+        $table[$base as usize + 2] = $handler::<2>;
+    };
+}
+
+macro_rules! two_params {
+    ($table:ident, $base:expr, $handler:ident $(, $cond:literal)?) => {
+        $table[$base as usize] = $handler::<$($cond,)? 0, 0>;
+        $table[$base as usize + 1] = $handler::<$($cond,)? 0, 1>;
+        $table[$base as usize + 2] = $handler::<$($cond,)? 0, 2>;
+        $table[$base as usize + 3] = $handler::<$($cond,)? 1, 0>;
+        $table[$base as usize + 4] = $handler::<$($cond,)? 1, 1>;
+        $table[$base as usize + 5] = $handler::<$($cond,)? 1, 2>;
+        $table[$base as usize + 6] = $handler::<$($cond,)? 2, 0>;
+        $table[$base as usize + 7] = $handler::<$($cond,)? 2, 1>;
+        $table[$base as usize + 8] = $handler::<$($cond,)? 2, 2>;
+    };
+}
+
+// Two read parameters, then one written with mode 0 or 2
+macro_rules! three_params {
+    ($table:ident, $base:expr, $handler:ident) => {
+        $table[$base as usize] = $handler::<0, 0, 0>;
+        $table[$base as usize + 1] = $handler::<0, 0, 2>;
+        $table[$base as usize + 2] = $handler::<0, 1, 0>;
+        $table[$base as usize + 3] = $handler::<0, 1, 2>;
+        $table[$base as usize + 4] = $handler::<0, 2, 0>;
+        $table[$base as usize + 5] = $handler::<0, 2, 2>;
+        $table[$base as usize + 6] = $handler::<1, 0, 0>;
+        $table[$base as usize + 7] = $handler::<1, 0, 2>;
+        $table[$base as usize + 8] = $handler::<1, 1, 0>;
+        $table[$base as usize + 9] = $handler::<1, 1, 2>;
+        $table[$base as usize + 10] = $handler::<1, 2, 0>;
+        $table[$base as usize + 11] = $handler::<1, 2, 2>;
+        $table[$base as usize + 12] = $handler::<2, 0, 0>;
+        $table[$base as usize + 13] = $handler::<2, 0, 2>;
+        $table[$base as usize + 14] = $handler::<2, 1, 0>;
+        $table[$base as usize + 15] = $handler::<2, 1, 2>;
+        $table[$base as usize + 16] = $handler::<2, 2, 0>;
+        $table[$base as usize + 17] = $handler::<2, 2, 2>;
+    };
+}
+
+// 256 entries so that indexing by a u8 code needs no bounds check
+const HANDLERS: [Handler; 256] = {
+    let mut table: [Handler; 256] = [decode_and_run; 256];
+    three_params!(table, ADD, add);
+    three_params!(table, MUL, mul);
+    three_params!(table, LESS_THAN, less_than);
+    three_params!(table, EQUALS, equals);
+    two_params!(table, JUMP_IF_TRUE, jump_if, true);
+    two_params!(table, JUMP_IF_FALSE, jump_if, false);
+    one_param!(table, OUTPUT, output);
+    one_param!(table, ADJUST_BASE, adjust_base);
+    table[INPUT as usize] = input::<0>;
+    table[INPUT as usize + 1] = input::<2>;
+    table[HALT as usize] = halt;
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm;
+    use super::*;
+
+    #[test]
+    fn test_codes() {
+        assert_eq!(code_for(1), Some(ADD));
+        assert_eq!(code_for(22202), Some(MUL + 17));
+        assert_eq!(code_for(1105), Some(JUMP_IF_TRUE + 4));
+        assert_eq!(code_for(203), Some(INPUT + 1));
+        assert_eq!(code_for(99), Some(HALT));
+        assert_eq!(code_for(11101), None);
+        assert_eq!(code_for(103), None);
+        assert_eq!(code_for(304), None);
+        assert_eq!(code_for(-1), None);
+        assert_eq!(code_for(42), None);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let program = asm::assemble(
+            "
+            start:  add #0, #0, result
+                    jt flag, #done
+                    add #1, #0, flag
+                    add #1102, #0, start    ; now mul #3, #4, result
+                    add #3, #0, start + 1
+                    add #4, #0, start + 2
+                    jt #1, #start
+            done:   hlt
+            result: data 0
+            flag:   data 0
+            ",
+        )
+        .unwrap();
+        let mut prog: IntCodeState = program.into();
+        let mut uncached = prog.clone();
+        uncached.disable_decode_cache();
+        prog.execute_until_halt_no_input();
+        assert_eq!(
+            prog.get_mem(prog.decode_at(0).unwrap().operands[2].value()),
+            12
+        );
+        uncached.execute_until_halt_no_input();
+        assert_eq!(uncached, prog);
+    }
+}
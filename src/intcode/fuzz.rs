@@ -0,0 +1,587 @@
+use super::memory::MAX_DENSE_MEMORY;
+use super::{IntCodeError, IntCodeErrorKind, IntCodeState, MemoryModel};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::iter::successors;
+
+// Differential testing of IntCodeState against a deliberately simple
+// reference interpreter, on random programs. Every instruction generated
+// is well formed, but operands point all over the program, so they also
+// exercise self-modifying code, relative addressing and runtime errors.
+//
+// Arithmetic which overflows panics in a debug build of IntCodeState and
+// wraps in a release build, so the reference stops with an Overflow error
+// instead, and runs which end that way aren't compared.
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct FuzzConfig {
+    // Most instructions in a program, not counting the final halt
+    pub max_instructions: usize,
+    // Words of data after the code, which most operands point into
+    pub data_words: usize,
+    // Most instructions to run, as generated programs often loop forever
+    pub fuel: u64,
+}
+
+impl Default for FuzzConfig {
+    fn default() -> Self {
+        FuzzConfig {
+            max_instructions: 24,
+            data_words: 8,
+            fuel: 2000,
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Case {
+    pub program: Vec<i64>,
+    pub inputs: Vec<i64>,
+    pub model: MemoryModel,
+    pub predecode: bool,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum End {
+    Halted,
+    NeedsInput,
+    OutOfFuel,
+    Error(IntCodeError),
+}
+
+// Everything a run is compared on
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Outcome {
+    pub end: End,
+    pub output: Vec<i64>,
+    // Only the words which aren't zero
+    pub memory: BTreeMap<i64, i64>,
+    pub instruction_ptr: i64,
+    pub base_ptr: i64,
+}
+
+#[derive(Debug)]
+pub struct Failure {
+    pub original: Case,
+    // The smallest case found which still fails
+    pub shrunk: Case,
+    pub expected: Outcome,
+    pub actual: Outcome,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |values: &[i64]| {
+            values
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        writeln!(f, "program: {}", join(&self.shrunk.program))?;
+        writeln!(f, "inputs: {}", join(&self.shrunk.inputs))?;
+        writeln!(
+            f,
+            "model: {:?}, predecoded: {}",
+            self.shrunk.model, self.shrunk.predecode
+        )?;
+        writeln!(f, "reference: {:?}", self.expected)?;
+        write!(f, "IntCodeState: {:?}", self.actual)
+    }
+}
+
+// SplitMix64, which is plenty for generating test cases
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn range(&mut self, low: i64, high: i64) -> i64 {
+        low + (self.next() % (high - low + 1) as u64) as i64
+    }
+
+    fn chance(&mut self, percent: u64) -> bool {
+        self.next() % 100 < percent
+    }
+}
+
+fn arity(opcode: i64) -> usize {
+    match opcode {
+        1 | 2 | 7 | 8 => 3,
+        5 | 6 => 2,
+        3 | 4 | 9 => 1,
+        _ => 0,
+    }
+}
+
+fn generate(rng: &mut Rng, config: &FuzzConfig) -> Case {
+    let count = rng.below(config.max_instructions) + 1;
+    let opcodes = (0..count)
+        .map(|_| match rng.below(20) {
+            0 => 99,
+            n => (n % 9) as i64 + 1,
+        })
+        .collect::<Vec<_>>();
+    let mut starts = vec![];
+    let mut code_len = 0;
+    for &opcode in opcodes.iter() {
+        starts.push(code_len as i64);
+        code_len += 1 + arity(opcode);
+    }
+    let data_start = code_len as i64 + 1;
+    let len = data_start + config.data_words as i64;
+
+    // Mostly somewhere in the data, sometimes anywhere
+    let address = |rng: &mut Rng| {
+        if rng.chance(75) {
+            rng.range(data_start, len - 1)
+        } else {
+            rng.range(0, len - 1)
+        }
+    };
+    let mut program = vec![];
+    for &opcode in opcodes.iter() {
+        let mut modes = vec![];
+        let mut operands = vec![];
+        for idx in 0..arity(opcode) {
+            let writes = matches!((opcode, idx), (1 | 2 | 7 | 8, 2) | (3, 0));
+            let mode = if writes {
+                [0, 2][rng.below(2)]
+            } else {
+                rng.below(3) as i64
+            };
+            operands.push(match mode {
+                0 => address(rng),
+                2 => rng.range(-4, len),
+                _ if matches!(opcode, 5 | 6) && idx == 1 => {
+                    if rng.chance(90) {
+                        starts[rng.below(starts.len())]
+                    } else {
+                        rng.range(-2, len)
+                    }
+                }
+                _ if rng.chance(5) => rng.next() as i64,
+                _ => rng.range(-10, 10),
+            });
+            modes.push(mode);
+        }
+        let mode_digits = modes
+            .iter()
+            .rev()
+            .fold(0, |digits, mode| digits * 10 + mode);
+        program.push(opcode + 100 * mode_digits);
+        program.extend(operands);
+    }
+    program.push(99);
+    program.extend((0..config.data_words).map(|_| rng.range(-10, 10)));
+
+    let inputs = (0..rng.below(count + 1))
+        .map(|_| rng.range(-10, 10))
+        .collect();
+    let model = match rng.below(4) {
+        0 => MemoryModel::Dense,
+        1 => MemoryModel::Sparse,
+        2 => MemoryModel::Paged,
+        // Sometimes too small to hold the whole program
+        _ => MemoryModel::Strict(rng.below(len as usize + 8)),
+    };
+    Case {
+        program,
+        inputs,
+        model,
+        predecode: rng.chance(50),
+    }
+}
+
+// Straight from the puzzle descriptions: no caching, no tricks, memory in a
+// map. Faults are detected before anything changes, as IntCodeState does.
+struct Reference {
+    memory: BTreeMap<i64, i64>,
+    limit: Option<i64>,
+    instruction_ptr: i64,
+    base_ptr: i64,
+    output: Vec<i64>,
+}
+
+impl Reference {
+    fn fault(&self, kind: IntCodeErrorKind, address: Option<i64>) -> IntCodeError {
+        IntCodeError {
+            kind,
+            instruction_ptr: self.instruction_ptr,
+            instruction: None,
+            address,
+        }
+    }
+
+    fn check(&self, address: i64) -> Result<(), IntCodeError> {
+        if address < 0 {
+            Err(self.fault(IntCodeErrorKind::NegativeAddress, Some(address)))
+        } else if self.limit.is_some_and(|limit| address >= limit) {
+            Err(self.fault(IntCodeErrorKind::OutOfBounds, Some(address)))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read(&self, address: i64) -> Result<i64, IntCodeError> {
+        self.check(address)?;
+        Ok(*self.memory.get(&address).unwrap_or(&0))
+    }
+
+    fn mode(instruction: i64, operand: u32) -> i64 {
+        (instruction / 10i64.pow(operand + 1)) % 10
+    }
+
+    fn checked(&self, result: Option<i64>) -> Result<i64, IntCodeError> {
+        result.ok_or_else(|| self.fault(IntCodeErrorKind::Overflow, None))
+    }
+
+    fn value(&self, instruction: i64, operand: u32) -> Result<i64, IntCodeError> {
+        let word = self.read(self.instruction_ptr + operand as i64)?;
+        match Reference::mode(instruction, operand) {
+            0 => self.read(word),
+            1 => Ok(word),
+            2 => self.read(self.checked(self.base_ptr.checked_add(word))?),
+            mode => Err(self.fault(IntCodeErrorKind::InvalidMode(mode as u32), None)),
+        }
+    }
+
+    fn target(&self, instruction: i64, operand: u32) -> Result<i64, IntCodeError> {
+        let word = self.read(self.instruction_ptr + operand as i64)?;
+        let address = match Reference::mode(instruction, operand) {
+            0 => word,
+            1 => return Err(self.fault(IntCodeErrorKind::ImmediateModeWrite, None)),
+            2 => self.checked(self.base_ptr.checked_add(word))?,
+            mode => return Err(self.fault(IntCodeErrorKind::InvalidMode(mode as u32), None)),
+        };
+        self.check(address)?;
+        Ok(address)
+    }
+
+    fn step(
+        &mut self,
+        inputs: &mut impl Iterator<Item = i64>,
+    ) -> Result<Option<End>, IntCodeError> {
+        let raw = self.read(self.instruction_ptr)?;
+        let ins = match u32::try_from(raw) {
+            Ok(ins) => ins as i64,
+            Err(_) => return Err(self.fault(IntCodeErrorKind::InvalidOpcode(raw), None)),
+        };
+        match ins % 100 {
+            opcode @ (1 | 2 | 7 | 8) => {
+                let x = self.value(ins, 1)?;
+                let y = self.value(ins, 2)?;
+                let dest = self.target(ins, 3)?;
+                let result = match opcode {
+                    1 => self.checked(x.checked_add(y))?,
+                    2 => self.checked(x.checked_mul(y))?,
+                    7 => (x < y) as i64,
+                    _ => (x == y) as i64,
+                };
+                self.memory.insert(dest, result);
+                self.instruction_ptr += 4;
+            }
+            3 => {
+                let dest = self.target(ins, 1)?;
+                match inputs.next() {
+                    Some(value) => self.memory.insert(dest, value),
+                    None => return Ok(Some(End::NeedsInput)),
+                };
+                self.instruction_ptr += 2;
+            }
+            4 => {
+                let value = self.value(ins, 1)?;
+                self.output.push(value);
+                self.instruction_ptr += 2;
+            }
+            opcode @ (5 | 6) => {
+                if (self.value(ins, 1)? != 0) == (opcode == 5) {
+                    self.instruction_ptr = self.value(ins, 2)?;
+                } else {
+                    self.instruction_ptr += 3;
+                }
+            }
+            9 => {
+                self.base_ptr = self.checked(self.base_ptr.checked_add(self.value(ins, 1)?))?;
+                self.instruction_ptr += 2;
+            }
+            99 => return Ok(Some(End::Halted)),
+            opcode => return Err(self.fault(IntCodeErrorKind::InvalidOpcode(opcode), None)),
+        }
+        Ok(None)
+    }
+}
+
+pub fn run_reference(case: &Case, fuel: u64) -> Outcome {
+    let mut vm = Reference {
+        memory: (0..).zip(case.program.iter().copied()).collect(),
+        limit: match case.model {
+            MemoryModel::Sparse => None,
+            MemoryModel::Strict(size) => Some(size.max(case.program.len()) as i64),
+            MemoryModel::Dense | MemoryModel::Paged => Some(MAX_DENSE_MEMORY as i64),
+        },
+        instruction_ptr: 0,
+        base_ptr: 0,
+        output: vec![],
+    };
+    let mut inputs = case.inputs.iter().copied();
+    let mut end = End::OutOfFuel;
+    for _ in 0..fuel {
+        match vm.step(&mut inputs) {
+            Ok(None) => {}
+            Ok(Some(reason)) => {
+                end = reason;
+                break;
+            }
+            Err(e) => {
+                let instruction = vm.read(vm.instruction_ptr).ok();
+                end = End::Error(IntCodeError { instruction, ..e });
+                break;
+            }
+        }
+    }
+    vm.memory.retain(|_, value| *value != 0);
+    Outcome {
+        end,
+        output: vm.output,
+        memory: vm.memory,
+        instruction_ptr: vm.instruction_ptr,
+        base_ptr: vm.base_ptr,
+    }
+}
+
+pub fn run_optimized(case: &Case, fuel: u64) -> Outcome {
+    let mut prog = IntCodeState::new(case.program.clone(), case.model);
+    if case.predecode {
+        prog.predecode();
+    }
+    let mut inputs = case.inputs.iter().copied();
+    let mut end = End::OutOfFuel;
+    for _ in 0..fuel {
+        let mut blocked = false;
+        let step = prog.try_execute_single_step(|_| {
+            let value = inputs.next();
+            blocked = value.is_none();
+            value
+        });
+        match step {
+            Ok(true) => end = End::Halted,
+            Ok(false) if blocked => end = End::NeedsInput,
+            Ok(false) => continue,
+            Err(e) => end = End::Error(e),
+        }
+        break;
+    }
+
+    let contiguous = prog.memory.contiguous();
+    let memory = (0..)
+        .zip(contiguous.iter().copied())
+        .chain(prog.memory.sparse().iter().map(|(&a, &v)| (a, v)))
+        .filter(|&(_, value)| value != 0)
+        .collect();
+    Outcome {
+        end,
+        output: prog.out_buffer.iter().copied().collect(),
+        memory,
+        instruction_ptr: prog.instruction_ptr,
+        base_ptr: prog.base_ptr,
+    }
+}
+
+// The outcomes from the reference and IntCodeState, if they differ. Runs
+// which overflow aren't compared, so IntCodeState isn't run at all.
+pub fn check(case: &Case, fuel: u64) -> Option<(Outcome, Outcome)> {
+    let expected = run_reference(case, fuel);
+    if matches!(&expected.end, End::Error(e) if e.kind == IntCodeErrorKind::Overflow) {
+        return None;
+    }
+    let actual = run_optimized(case, fuel);
+    (expected != actual).then_some((expected, actual))
+}
+
+// Greedily simplifies the case while it still fails: dropping inputs,
+// removing runs of words from the program, and moving words towards zero.
+// The result is minimal in that no single one of these steps still fails.
+pub fn shrink<F>(mut case: Case, fails: F) -> Case
+where
+    F: Fn(&Case) -> bool,
+{
+    let attempt = |case: &mut Case, candidate: Case| {
+        let better = candidate != *case && fails(&candidate);
+        if better {
+            *case = candidate;
+        }
+        better
+    };
+
+    loop {
+        let mut progress = false;
+
+        let candidate = Case {
+            model: MemoryModel::Dense,
+            ..case.clone()
+        };
+        progress |= attempt(&mut case, candidate);
+        let candidate = Case {
+            predecode: false,
+            ..case.clone()
+        };
+        progress |= attempt(&mut case, candidate);
+
+        let mut idx = 0;
+        while idx < case.inputs.len() {
+            let mut candidate = case.clone();
+            candidate.inputs.remove(idx);
+            if !attempt(&mut case, candidate) {
+                idx += 1;
+            } else {
+                progress = true;
+            }
+        }
+
+        // Halves, quarters and so on, then each width of instruction
+        let mut sizes = vec![1, 2, 3, 4];
+        sizes.extend(
+            successors(Some(case.program.len() / 2), |&size| Some(size / 2))
+                .take_while(|&size| size > 4),
+        );
+        sizes.sort_unstable_by(|a, b| b.cmp(a));
+        for size in sizes {
+            let mut start = 0;
+            while start + size <= case.program.len() {
+                let mut candidate = case.clone();
+                candidate.program.drain(start..start + size);
+                if !attempt(&mut case, candidate) {
+                    start += 1;
+                } else {
+                    progress = true;
+                }
+            }
+        }
+
+        for idx in 0..case.program.len() {
+            loop {
+                let value = case.program[idx];
+                let simpler = [0, value / 2, value - value.signum()];
+                let better = simpler.into_iter().filter(|&v| v != value).any(|v| {
+                    let mut candidate = case.clone();
+                    candidate.program[idx] = v;
+                    attempt(&mut case, candidate)
+                });
+                if !better {
+                    break;
+                }
+                progress = true;
+            }
+        }
+
+        if !progress {
+            return case;
+        }
+    }
+}
+
+// Runs `runs` random programs, stopping at the first which behaves
+// differently on IntCodeState, shrunk to a minimal reproduction. The same
+// seed always generates the same programs.
+pub fn fuzz(seed: u64, runs: usize, config: &FuzzConfig) -> Result<(), Box<Failure>> {
+    let mut rng = Rng(seed);
+    for _ in 0..runs {
+        let case = generate(&mut rng, config);
+        if check(&case, config.fuel).is_some() {
+            let shrunk = shrink(case.clone(), |c| check(c, config.fuel).is_some());
+            let (expected, actual) = check(&shrunk, config.fuel).expect("shrunk case should fail");
+            return Err(Box::new(Failure {
+                original: case,
+                shrunk,
+                expected,
+                actual,
+            }));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generates_valid_programs() {
+        let mut rng = Rng(1);
+        let config = FuzzConfig::default();
+        let mut halted = 0;
+        let mut overflowed = 0;
+        let mut strict_faults = 0;
+        for _ in 0..200 {
+            let case = generate(&mut rng, &config);
+            let outcome = run_reference(&case, config.fuel);
+            // Every instruction decodes until the program changes itself
+            let prog: IntCodeState = case.program.clone().into();
+            let mut address = 0;
+            while prog.get_mem(address) != 99 {
+                let instruction = prog.decode_executable_at(address).unwrap();
+                address += instruction.operands.len() as i64 + 1;
+            }
+            halted += (outcome.end == End::Halted) as usize;
+            match (&outcome.end, case.model) {
+                (End::Error(e), _) if e.kind == IntCodeErrorKind::Overflow => overflowed += 1,
+                (End::Error(e), MemoryModel::Strict(_))
+                    if e.kind == IntCodeErrorKind::OutOfBounds =>
+                {
+                    strict_faults += 1
+                }
+                _ => {}
+            }
+        }
+        assert!(halted > 20);
+        assert!(strict_faults > 0);
+        assert!(overflowed < 20);
+    }
+
+    #[test]
+    fn test_no_differences() {
+        let config = FuzzConfig::default();
+        if let Err(failure) = fuzz(2019, 2000, &config) {
+            panic!("{}", failure);
+        }
+    }
+
+    #[test]
+    fn test_shrink() {
+        // Pretend there's a bug in how negative numbers are output
+        let fails = |case: &Case| {
+            run_reference(case, 100)
+                .output
+                .iter()
+                .any(|&value| value < -3)
+        };
+        let case = Case {
+            program: vec![
+                1101, 6, 7, 17, 4, 17, 1002, 17, -2, 18, 4, 18, 104, 5, 99, 0, 0, 0, 0,
+            ],
+            inputs: vec![1, 2, 3],
+            model: MemoryModel::Paged,
+            predecode: true,
+        };
+        assert!(fails(&case));
+        let shrunk = shrink(case, fails);
+        // Multiplies its own first word by -1 and outputs it
+        assert_eq!(shrunk.program, vec![1002, 0, -1, 18, 4, 18]);
+        assert!(shrunk.inputs.is_empty());
+        assert_eq!(
+            (shrunk.model, shrunk.predecode),
+            (MemoryModel::Dense, false)
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use super::IntCodeState;
+use std::collections::VecDeque;
+
+// Enough to reverse one executed instruction
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub(super) struct UndoEntry {
+    pub instruction_ptr: i64,
+    pub base_ptr: i64,
+    // Address and the value it held before
+    pub write: Option<(i64, i64)>,
+    pub input: Option<i64>,
+    // Value output, and the length of out_buffer just after adding it
+    pub output: Option<(i64, usize)>,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub(super) struct History {
+    depth: usize,
+    entries: VecDeque<UndoEntry>,
+}
+
+impl History {
+    pub fn push(&mut self, entry: UndoEntry) {
+        if self.entries.len() == self.depth {
+            self.entries.pop_front();
+        }
+        if self.depth > 0 {
+            self.entries.push_back(entry);
+        }
+    }
+}
+
+impl IntCodeState {
+    // Records executed instructions so that up to `depth` of them can be
+    // undone with step_back. Changing the depth keeps as much of the recent
+    // history as fits. Changes made from outside the program, such as with
+    // set_mem, aren't recorded. Panics if custom opcodes are registered,
+    // as their effects can't be undone.
+    pub fn enable_history(&mut self, depth: usize) {
+        assert!(
+            self.custom_opcodes.is_empty(),
+            "can't enable undo history with custom opcodes registered"
+        );
+        let inner = self.instruments.get_mut();
+        let mut entries = inner
+            .history
+            .take()
+            .map_or_else(VecDeque::new, |h| h.entries);
+        while entries.len() > depth {
+            entries.pop_front();
+        }
+        inner.history = Some(History { depth, entries });
+    }
+
+    pub fn disable_history(&mut self) {
+        self.instruments.get_mut().history = None;
+        self.instruments.prune();
+    }
+
+    pub fn has_history(&self) -> bool {
+        self.instruments.get().is_some_and(|i| i.history.is_some())
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.instruments
+            .get()
+            .and_then(|i| i.history.as_ref())
+            .map_or(0, |h| h.entries.len())
+    }
+
+    // Reverses the most recently executed instruction, returning false if
+    // there's no history left. Input it read goes back to the front of
+    // in_buffer, and output it produced is removed from out_buffer if it's
+    // still the last value there.
+    pub fn step_back(&mut self) -> bool {
+        let Some(entry) = self
+            .instruments
+            .get_mut()
+            .history
+            .as_mut()
+            .and_then(|h| h.entries.pop_back())
+        else {
+            self.instruments.prune();
+            return false;
+        };
+
+        self.instruction_ptr = entry.instruction_ptr;
+        self.base_ptr = entry.base_ptr;
+        if let Some((address, old)) = entry.write {
+            self.store(address, old);
+        }
+        if let Some(input) = entry.input {
+            self.in_buffer.push_front(input);
+        }
+        if let Some((value, len)) = entry.output {
+            if self.out_buffer.len() == len && self.out_buffer.back() == Some(&value) {
+                self.out_buffer.pop_back();
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::IntCodeEvent;
+    use super::*;
+
+    #[test]
+    fn test_step_back_to_start() {
+        let program = vec![109, 5, 3, 0, 21101, 1, 2, 6, 204, 6, 99, 0];
+        let original: IntCodeState = program.into();
+        let mut prog = original.clone();
+        prog.enable_history(100);
+        prog.in_buffer.push_back(7);
+        prog.run_until_event();
+        prog.out_buffer.push_back(8);
+        assert_eq!(prog.run_until_event(), IntCodeEvent::Halted);
+
+        assert_eq!(prog.history_len(), 4);
+        while prog.step_back() {}
+        assert_eq!(prog.in_buffer, VecDeque::from([7]));
+        assert_eq!(prog.out_buffer, VecDeque::from([8]));
+        prog.out_buffer.clear();
+        prog.in_buffer.clear();
+        assert_eq!(prog, original);
+    }
+
+    #[test]
+    fn test_step_back_and_replay() {
+        let mut prog: IntCodeState = vec![3, 0, 1001, 0, 5, 0, 4, 0, 99].into();
+        prog.enable_history(10);
+        prog.execute_until_halt(|_| Some(2));
+        assert_eq!(prog.out_buffer, VecDeque::from([7]));
+
+        assert!(prog.step_back());
+        assert!(prog.out_buffer.is_empty());
+        assert_eq!(prog.instruction_ptr(), 6);
+        assert!(prog.step_back());
+        assert_eq!(prog.get_mem(0), 2);
+
+        prog.execute_until_halt(|_| panic!("should not ask for input"));
+        assert_eq!(prog.out_buffer, VecDeque::from([7]));
+    }
+
+    #[test]
+    fn test_history_depth() {
+        let mut prog: IntCodeState = vec![1101, 1, 1, 0, 1105, 1, 0].into();
+        prog.enable_history(3);
+        for _ in 0..10 {
+            prog.execute_single_step(|_| None);
+        }
+        assert_eq!(prog.history_len(), 3);
+        prog.enable_history(1);
+        assert_eq!(prog.history_len(), 1);
+        assert!(prog.step_back());
+        assert!(!prog.step_back());
+        prog.disable_history();
+        assert!(!prog.instruments.is_active());
+    }
+}
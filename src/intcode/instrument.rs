@@ -0,0 +1,255 @@
+use super::custom::OperandKind;
+use super::disasm::{DecodedInstruction, Opcode, Operand};
+use super::history::{History, UndoEntry};
+use super::profile::Profile;
+use super::trace::{MemoryWrite, TraceRecord, TraceSink};
+use super::watch::{AccessKind, MemoryAccess, Watches};
+use super::{IntCodeError, IntCodeState, Step};
+use std::fmt;
+use std::io;
+
+// Optional observers of execution, which are only consulted while at least
+// one is enabled. They aren't part of the VM state, so aren't cloned along
+// with it and are ignored when comparing states.
+#[derive(Default)]
+pub(super) struct Instruments(Option<Box<Instrumentation>>);
+
+#[derive(Default)]
+pub(super) struct Instrumentation {
+    pub trace: Option<Box<dyn TraceSink + Send + Sync>>,
+    pub trace_error: Option<io::Error>,
+    pub profile: Option<Profile>,
+    pub history: Option<History>,
+    pub watches: Option<Watches>,
+}
+
+impl Instrumentation {
+    fn is_empty(&self) -> bool {
+        self.trace.is_none()
+            && self.trace_error.is_none()
+            && self.profile.is_none()
+            && self.history.is_none()
+            && self.watches.is_none()
+    }
+}
+
+impl Instruments {
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn get(&self) -> Option<&Instrumentation> {
+        self.0.as_deref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut Instrumentation {
+        self.0.get_or_insert_with(Box::default)
+    }
+
+    // Drops back to the uninstrumented fast path once nothing is enabled
+    pub fn prune(&mut self) {
+        if self.0.as_ref().is_some_and(|inner| inner.is_empty()) {
+            self.0 = None;
+        }
+    }
+}
+
+impl Clone for Instruments {
+    fn clone(&self) -> Self {
+        Instruments(None)
+    }
+}
+
+impl PartialEq for Instruments {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Instruments {}
+
+impl fmt::Debug for Instruments {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.is_active() {
+            "Instruments"
+        } else {
+            "None"
+        })
+    }
+}
+
+// What an instruction is about to do, worked out before executing it
+struct Pending {
+    instruction: DecodedInstruction,
+    values: Vec<i64>,
+    // Address and value of each operand read from memory
+    reads: Vec<(i64, i64)>,
+    write_address: Option<i64>,
+    old_value: i64,
+    base_ptr: i64,
+}
+
+impl IntCodeState {
+    fn observe(&self) -> Option<Pending> {
+        let instruction = self.decode_executable_at(self.instruction_ptr)?;
+        let mut values = Vec::with_capacity(instruction.operands.len());
+        let mut reads = vec![];
+        let mut write_address = None;
+        // Whether each operand is written through. Only the first of a
+        // custom instruction's writes is recorded.
+        let writes = match instruction.opcode {
+            Opcode::Custom(code) => self
+                .custom_opcodes
+                .get(code)?
+                .operands
+                .iter()
+                .map(|&kind| kind == OperandKind::Write)
+                .collect(),
+            opcode => (0..opcode.arity())
+                .map(|idx| opcode.write_operand() == Some(idx))
+                .collect::<Vec<_>>(),
+        };
+
+        for (idx, operand) in instruction.operands.iter().enumerate() {
+            // A jump only reads its target if it's taken
+            let untaken = match (idx, instruction.opcode) {
+                (1, Opcode::JumpIfTrue) => values[0] == 0,
+                (1, Opcode::JumpIfFalse) => values[0] != 0,
+                _ => false,
+            };
+            if untaken {
+                break;
+            }
+            let address = match *operand {
+                Operand::Position(pos) => Some(pos),
+                Operand::Immediate(_) => None,
+                Operand::Relative(pos) => Some(self.base_ptr.checked_add(pos)?),
+            };
+            if writes[idx] {
+                write_address = write_address.or(address);
+                values.push(address?);
+            } else if let Some(address) = address {
+                let value = self.try_get_mem(address).ok()?;
+                reads.push((address, value));
+                values.push(value);
+            } else {
+                values.push(operand.value());
+            }
+        }
+
+        let old_value = match write_address {
+            Some(address) => self.try_get_mem(address).ok()?,
+            None => 0,
+        };
+
+        Some(Pending {
+            instruction,
+            values,
+            reads,
+            write_address,
+            old_value,
+            base_ptr: self.base_ptr,
+        })
+    }
+
+    pub(super) fn step_instrumented<F>(&mut self, input_handler: F) -> Result<Step, IntCodeError>
+    where
+        F: FnMut(&mut IntCodeState) -> Option<i64>,
+    {
+        let instruction_ptr = self.instruction_ptr;
+        let opcode = self
+            .try_get_mem(instruction_ptr)
+            .ok()
+            .and_then(|raw| u32::try_from(raw % 100).ok())
+            .and_then(|code| {
+                Opcode::from_code(code)
+                    .or_else(|| self.custom_opcodes.get(code).map(|_| Opcode::Custom(code)))
+            });
+        let base_ptr = self.base_ptr;
+        let (tracing, recording, watching) =
+            self.instruments.get().map_or((false, false, false), |i| {
+                (i.trace.is_some(), i.history.is_some(), i.watches.is_some())
+            });
+        let pending = if tracing || recording || watching {
+            self.observe()
+        } else {
+            None
+        };
+
+        let result = self.step_plain(input_handler);
+
+        if !matches!(result, Ok(step) if step != Step::Blocked) {
+            return result;
+        }
+
+        let undo = match (&result, &pending) {
+            (Ok(Step::Halted), _) | (_, None) => None,
+            (_, Some(pending)) => Some(UndoEntry {
+                instruction_ptr,
+                base_ptr,
+                write: pending
+                    .write_address
+                    .map(|address| (address, pending.old_value)),
+                input: (pending.instruction.opcode == Opcode::In)
+                    .then(|| self.get_mem(pending.values[0])),
+                output: (pending.instruction.opcode == Opcode::Out)
+                    .then(|| (pending.values[0], self.out_buffer.len())),
+            }),
+        };
+        let accesses = match pending.as_ref().filter(|_| watching) {
+            Some(pending) => {
+                let reads = pending.reads.iter().map(|&(address, value)| MemoryAccess {
+                    instruction_ptr,
+                    address,
+                    kind: AccessKind::Read,
+                    old: value,
+                    new: value,
+                });
+                let write = pending.write_address.map(|address| MemoryAccess {
+                    instruction_ptr,
+                    address,
+                    kind: AccessKind::Write,
+                    old: pending.old_value,
+                    new: self.get_mem(address),
+                });
+                reads.chain(write).collect()
+            }
+            None => vec![],
+        };
+        let record = pending.filter(|_| tracing).map(|pending| TraceRecord {
+            instruction_ptr,
+            instruction: pending.instruction,
+            values: pending.values,
+            write: pending.write_address.map(|address| MemoryWrite {
+                address,
+                old: pending.old_value,
+                new: self.get_mem(address),
+            }),
+            base_change: (pending.base_ptr != self.base_ptr)
+                .then_some((pending.base_ptr, self.base_ptr)),
+        });
+        let next_ptr = self.instruction_ptr;
+
+        let inner = self.instruments.get_mut();
+        if let (Some(profile), Some(opcode)) = (inner.profile.as_mut(), opcode) {
+            profile.record(instruction_ptr, opcode, next_ptr);
+        }
+        if let (Some(history), Some(undo)) = (inner.history.as_mut(), undo) {
+            history.push(undo);
+        }
+        if let Some(watches) = inner.watches.as_mut() {
+            for access in accesses.iter() {
+                watches.notify(access);
+            }
+        }
+        if let (Some(sink), Some(record)) = (inner.trace.as_mut(), record) {
+            if let Err(e) = sink.record(&record) {
+                inner.trace = None;
+                inner.trace_error = Some(e);
+            }
+        }
+
+        result
+    }
+}
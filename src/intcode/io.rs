@@ -0,0 +1,249 @@
+use super::ascii::{encode_line, AsciiOutput};
+use super::{IntCodeError, IntCodeEvent, IntCodeState, Step};
+use std::collections::VecDeque;
+use std::sync::mpsc::{Receiver, Sender};
+
+// Where a program's input comes from and its output goes to. Closures taking
+// the state and returning the next input implement it too, leaving output in
+// out_buffer as the execute methods always have.
+pub trait IntcodeIo {
+    // The next input, or None if there isn't one yet
+    fn input(&mut self, state: &mut IntCodeState) -> Option<i64>;
+
+    fn output(&mut self, state: &mut IntCodeState, value: i64) {
+        state.out_buffer.push_back(value);
+    }
+}
+
+impl<F> IntcodeIo for F
+where
+    F: FnMut(&mut IntCodeState) -> Option<i64>,
+{
+    fn input(&mut self, state: &mut IntCodeState) -> Option<i64> {
+        self(state)
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct QueueIo {
+    pub input: VecDeque<i64>,
+    pub output: VecDeque<i64>,
+}
+
+impl IntcodeIo for QueueIo {
+    fn input(&mut self, _: &mut IntCodeState) -> Option<i64> {
+        self.input.pop_front()
+    }
+
+    fn output(&mut self, _: &mut IntCodeState, value: i64) {
+        self.output.push_back(value);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IterIo<I> {
+    pub input: I,
+    pub output: Vec<i64>,
+}
+
+impl<I: Iterator<Item = i64>> IterIo<I> {
+    pub fn new<T: IntoIterator<IntoIter = I>>(input: T) -> Self {
+        IterIo {
+            input: input.into_iter(),
+            output: vec![],
+        }
+    }
+}
+
+impl<I: Iterator<Item = i64>> IntcodeIo for IterIo<I> {
+    fn input(&mut self, _: &mut IntCodeState) -> Option<i64> {
+        self.input.next()
+    }
+
+    fn output(&mut self, _: &mut IntCodeState, value: i64) {
+        self.output.push(value);
+    }
+}
+
+// For running programs on their own threads. Waiting for input blocks the
+// thread; the program stops once every sender of its input has gone.
+// Output sent after the receiver has gone is dropped.
+#[derive(Debug)]
+pub struct ChannelIo {
+    pub input: Receiver<i64>,
+    pub output: Sender<i64>,
+}
+
+impl IntcodeIo for ChannelIo {
+    fn input(&mut self, _: &mut IntCodeState) -> Option<i64> {
+        self.input.recv().ok()
+    }
+
+    fn output(&mut self, _: &mut IntCodeState, value: i64) {
+        let _ = self.output.send(value);
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct AsciiIo {
+    pub input: VecDeque<i64>,
+    pub output: AsciiOutput,
+}
+
+impl AsciiIo {
+    // Queues the line for the program, adding the newline
+    pub fn send_line(&mut self, line: &str) {
+        self.input.extend(encode_line(line));
+    }
+
+    pub fn take_output(&mut self) -> AsciiOutput {
+        std::mem::take(&mut self.output)
+    }
+}
+
+impl IntcodeIo for AsciiIo {
+    fn input(&mut self, _: &mut IntCodeState) -> Option<i64> {
+        self.input.pop_front()
+    }
+
+    fn output(&mut self, _: &mut IntCodeState, value: i64) {
+        self.output.push(value);
+    }
+}
+
+impl IntCodeState {
+    // Runs until the program halts or `io` has no input for it, returning
+    // Halted or NeedsInput accordingly. Calling it again retries the input.
+    pub fn try_run_io<T>(&mut self, io: &mut T) -> Result<IntCodeEvent, IntCodeError>
+    where
+        T: IntcodeIo + ?Sized,
+    {
+        loop {
+            match self.step(|state| io.input(state))? {
+                Step::Continue => {}
+                Step::Blocked => return Ok(IntCodeEvent::NeedsInput),
+                Step::Output => {
+                    let value = self.out_buffer.pop_back().expect("output should exist");
+                    io.output(self, value);
+                }
+                Step::Halted => return Ok(IntCodeEvent::Halted),
+            }
+        }
+    }
+
+    pub fn run_io<T>(&mut self, io: &mut T) -> IntCodeEvent
+    where
+        T: IntcodeIo + ?Sized,
+    {
+        self.try_run_io(io).unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    // Adds one to each input until it reads a 0
+    fn incrementer() -> IntCodeState {
+        assemble(
+            "
+            loop:   in x
+                    jt x, #inc
+                    hlt
+            inc:    add x, #1, x
+                    out x
+                    jt #1, #loop
+            x:      data 0
+            ",
+        )
+        .unwrap()
+        .into()
+    }
+
+    #[test]
+    fn test_queue_and_iter() {
+        let mut prog = incrementer();
+        let mut io = QueueIo::default();
+        io.input.extend([1, 2]);
+        assert_eq!(prog.run_io(&mut io), IntCodeEvent::NeedsInput);
+        assert_eq!(io.output, VecDeque::from([2, 3]));
+        io.input.push_back(0);
+        assert_eq!(prog.run_io(&mut io), IntCodeEvent::Halted);
+        assert!(prog.out_buffer.is_empty());
+
+        let mut io = IterIo::new((1..=3).chain(Some(0)));
+        assert_eq!(incrementer().run_io(&mut io), IntCodeEvent::Halted);
+        assert_eq!(io.output, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn test_closure() {
+        let mut prog = incrementer();
+        let mut inputs = vec![0, 5];
+        let mut io = |_: &mut IntCodeState| inputs.pop();
+        assert_eq!(prog.run_io(&mut io), IntCodeEvent::Halted);
+        assert_eq!(prog.out_buffer, VecDeque::from([6]));
+    }
+
+    #[test]
+    fn test_channels() {
+        let (tx, first_rx) = mpsc::channel();
+        let (first_tx, second_rx) = mpsc::channel();
+        let (second_tx, rx) = mpsc::channel();
+        let first = thread::spawn(move || {
+            let mut io = ChannelIo {
+                input: first_rx,
+                output: first_tx,
+            };
+            incrementer().run_io(&mut io)
+        });
+        let second = thread::spawn(move || {
+            let mut io = ChannelIo {
+                input: second_rx,
+                output: second_tx,
+            };
+            incrementer().run_io(&mut io)
+        });
+        for value in [1, 2, 3] {
+            tx.send(value).unwrap();
+        }
+        drop(tx);
+
+        assert_eq!(first.join().unwrap(), IntCodeEvent::NeedsInput);
+        assert_eq!(second.join().unwrap(), IntCodeEvent::NeedsInput);
+        assert_eq!(rx.iter().collect::<Vec<_>>(), vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn test_ascii() {
+        // Echoes a line back then outputs its length plus 200
+        let mut prog: IntCodeState = assemble(
+            "
+            loop:   in c
+                    out c
+                    eq c, #10, t
+                    jt t, #done
+                    add n, #1, n
+                    jt #1, #loop
+            done:   out n
+                    hlt
+            c:      data 0
+            t:      data 0
+            n:      data 200
+            ",
+        )
+        .unwrap()
+        .into();
+        let mut io = AsciiIo::default();
+        assert_eq!(prog.run_io(&mut io), IntCodeEvent::NeedsInput);
+        io.send_line("hi");
+        assert_eq!(prog.run_io(&mut io), IntCodeEvent::Halted);
+        let output = io.take_output();
+        assert_eq!(output.text, "hi\n");
+        assert_eq!(output.values, vec![202]);
+        assert_eq!(io.output, AsciiOutput::default());
+    }
+}
@@ -0,0 +1,204 @@
+use super::memory::Memory;
+use super::{IntCodeError, IntCodeState, Step};
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Default)]
+pub struct Limits {
+    // Most instructions to attempt, counting those blocked waiting for input
+    pub fuel: Option<u64>,
+    // Checks whether the machine gets back to a state it has already been in
+    // without reading input, which proves it will never halt. Costs a
+    // comparison of memory every time the instruction pointer and base
+    // match those in a saved state, plus saving a copy of memory every time
+    // the number of instructions since the last input doubles.
+    pub detect_loops: bool,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum RunOutcome {
+    Halted,
+    OutOfFuel,
+    // The machine was in the same state `period` instructions earlier, with
+    // no input read in between. Outputs don't affect what the program does,
+    // so this also catches programs stuck printing the same thing forever.
+    InfiniteLoop { instruction_ptr: i64, period: u64 },
+}
+
+// Brent's cycle detection: compares each state with one saved at the last
+// power of two steps since the detector was reset, which finds any cycle
+// within a couple of its periods of it starting.
+struct LoopDetector {
+    saved: Option<(i64, i64, Memory)>,
+    power: u64,
+    steps: u64,
+}
+
+impl LoopDetector {
+    fn new() -> Self {
+        LoopDetector {
+            saved: None,
+            power: 1,
+            steps: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = LoopDetector::new();
+    }
+
+    // Period of the loop, if the state has been seen before
+    fn check(&mut self, state: &IntCodeState) -> Option<u64> {
+        if let Some((ip, base, memory)) = &self.saved {
+            if *ip == state.instruction_ptr && *base == state.base_ptr && *memory == state.memory {
+                return Some(self.steps);
+            }
+        }
+        if self.saved.is_none() || self.steps == self.power {
+            self.saved = Some((state.instruction_ptr, state.base_ptr, state.memory.clone()));
+            self.power *= 2;
+            self.steps = 0;
+        }
+        self.steps += 1;
+        None
+    }
+}
+
+impl IntCodeState {
+    // Like try_execute_until_halt, but gives up once the fuel runs out or
+    // the program is found to be stuck in a loop. Without any limits it is
+    // no different, other than being slightly slower.
+    pub fn try_execute_with_limits<F>(
+        &mut self,
+        limits: Limits,
+        mut input_handler: F,
+    ) -> Result<RunOutcome, IntCodeError>
+    where
+        F: FnMut(&mut IntCodeState) -> Option<i64>,
+    {
+        let mut detector = limits.detect_loops.then(LoopDetector::new);
+        let mut used = 0;
+        loop {
+            if limits.fuel.is_some_and(|fuel| used >= fuel) {
+                return Ok(RunOutcome::OutOfFuel);
+            }
+            used += 1;
+
+            if let Some(detector) = detector.as_mut() {
+                if let Some(period) = detector.check(self) {
+                    return Ok(RunOutcome::InfiniteLoop {
+                        instruction_ptr: self.instruction_ptr,
+                        period,
+                    });
+                }
+            }
+
+            let mut read_input = false;
+            let step = self.step(|state| {
+                let value = input_handler(state);
+                read_input |= value.is_some();
+                value
+            })?;
+            match step {
+                Step::Halted => return Ok(RunOutcome::Halted),
+                // Waiting on input doesn't prove anything, as the input
+                // handler may have something next time
+                Step::Blocked => detector.iter_mut().for_each(LoopDetector::reset),
+                _ if read_input => detector.iter_mut().for_each(LoopDetector::reset),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn execute_with_limits<F>(&mut self, limits: Limits, input_handler: F) -> RunOutcome
+    where
+        F: FnMut(&mut IntCodeState) -> Option<i64>,
+    {
+        self.try_execute_with_limits(limits, input_handler)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::super::MemoryModel;
+    use super::*;
+
+    #[test]
+    fn test_fuel() {
+        let mut prog: IntCodeState = vec![1105, 1, 0].into();
+        let limits = Limits {
+            fuel: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!(
+            prog.execute_with_limits(limits, |_| None),
+            RunOutcome::OutOfFuel
+        );
+
+        let mut prog: IntCodeState = vec![1101, 1, 2, 5, 99, 0].into();
+        let limits = Limits {
+            fuel: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            prog.execute_with_limits(limits, |_| None),
+            RunOutcome::Halted
+        );
+        assert_eq!(prog.get_mem(5), 3);
+    }
+
+    #[test]
+    fn test_detects_loops() {
+        // Counts to 5, then loops forever printing the count
+        let program = assemble(
+            "
+            count:  add n, #1, n
+                    lt n, #5, more
+                    jt more, #count
+            stuck:  out n
+                    jt #1, #stuck
+            n:      data 0
+            more:   data 0
+            ",
+        )
+        .unwrap();
+        let limits = Limits {
+            detect_loops: true,
+            ..Default::default()
+        };
+        for model in [MemoryModel::Dense, MemoryModel::Sparse, MemoryModel::Paged] {
+            let mut prog = IntCodeState::new(program.clone(), model);
+            match prog.execute_with_limits(limits, |_| None) {
+                RunOutcome::InfiniteLoop { period, .. } => assert_eq!(period, 2),
+                other => panic!("{:?}", other),
+            }
+            assert_eq!(prog.get_mem(16), 5);
+        }
+    }
+
+    #[test]
+    fn test_input_is_not_a_loop() {
+        // Echoes its input until it reads a 0
+        let program = assemble(
+            "
+            loop:   in x
+                    out x
+                    jt x, #loop
+                    hlt
+            x:      data 0
+            ",
+        )
+        .unwrap();
+        let mut prog: IntCodeState = program.into();
+        let mut inputs = vec![0, 1, 1, 1, 1, 1, 1, 1];
+        let limits = Limits {
+            fuel: Some(1000),
+            detect_loops: true,
+        };
+        assert_eq!(
+            prog.execute_with_limits(limits, |_| inputs.pop()),
+            RunOutcome::Halted
+        );
+        assert_eq!(prog.out_buffer.len(), 8);
+    }
+}
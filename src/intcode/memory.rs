@@ -0,0 +1,267 @@
+use super::word::Word;
+use super::IntCodeErrorKind;
+use ahash::AHashMap;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+// Dense memory will refuse to grow past this many words (512MiB of i64s),
+// so a stray huge address fails cleanly rather than aborting on allocation.
+// Programs which really need such addresses should use MemoryModel::Sparse.
+pub(super) const MAX_DENSE_MEMORY: usize = 1 << 26;
+
+const PAGE_SIZE: usize = 256;
+
+type Page<W> = [W; PAGE_SIZE];
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Default)]
+pub enum MemoryModel {
+    // Fixed amount of memory (or the program length, if larger).
+    // Any access beyond it is an error.
+    Strict(usize),
+    // Contiguous memory which grows to fit the highest address written.
+    #[default]
+    Dense,
+    // Program in contiguous memory, anything beyond it in a hash map.
+    Sparse,
+    // Grows like Dense, but in fixed-size pages which clones share until
+    // one of them writes to the page. Slower to run than Dense, but makes
+    // forking thousands of VMs cheap.
+    Paged,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Memory<W = i64> {
+    model: MemoryModel,
+    dense: Vec<W>,
+    sparse: AHashMap<i64, W>,
+    pages: Vec<Arc<Page<W>>>,
+    high_water_mark: usize,
+}
+
+fn empty_page<W: Word>() -> Page<W> {
+    std::array::from_fn(|_| W::from_i64(0))
+}
+
+fn paginate<W: Word>(words: &[W]) -> Vec<Arc<Page<W>>> {
+    words
+        .chunks(PAGE_SIZE)
+        .map(|chunk| {
+            let mut page = empty_page();
+            page[..chunk.len()].clone_from_slice(chunk);
+            Arc::new(page)
+        })
+        .collect()
+}
+
+impl<W: Word> Memory<W> {
+    pub fn new(program: Vec<W>, model: MemoryModel) -> Self {
+        let high_water_mark = program.len();
+        Memory::from_parts(model, program, AHashMap::default(), high_water_mark)
+    }
+
+    // For restoring a snapshot, which must already have been validated
+    pub fn from_parts(
+        model: MemoryModel,
+        dense: Vec<W>,
+        sparse: AHashMap<i64, W>,
+        high_water_mark: usize,
+    ) -> Self {
+        let (dense, pages) = match model {
+            MemoryModel::Paged => (vec![], paginate(&dense)),
+            _ => (dense, vec![]),
+        };
+        Memory {
+            model,
+            dense,
+            sparse,
+            pages,
+            high_water_mark,
+        }
+    }
+
+    // Everything except sparse memory, as one contiguous block
+    pub fn contiguous(&self) -> Cow<'_, [W]> {
+        match self.model {
+            MemoryModel::Paged => {
+                let len = (self.pages.len() * PAGE_SIZE).min(self.high_water_mark);
+                Cow::Owned(
+                    self.pages
+                        .iter()
+                        .flat_map(|p| p.iter())
+                        .take(len)
+                        .cloned()
+                        .collect(),
+                )
+            }
+            _ => Cow::Borrowed(&self.dense),
+        }
+    }
+
+    pub fn sparse(&self) -> &AHashMap<i64, W> {
+        &self.sparse
+    }
+
+    pub fn model(&self) -> MemoryModel {
+        self.model
+    }
+
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water_mark
+    }
+
+    #[inline]
+    pub fn check(&self, address: i64) -> Result<(), IntCodeErrorKind> {
+        if (address as u64) < (self.dense.len() as u64) {
+            Ok(())
+        } else {
+            self.check_slow(address)
+        }
+    }
+
+    #[cold]
+    fn check_slow(&self, address: i64) -> Result<(), IntCodeErrorKind> {
+        if address < 0 {
+            return Err(IntCodeErrorKind::NegativeAddress);
+        }
+        match self.model {
+            MemoryModel::Strict(size) if address as usize >= size => {
+                Err(IntCodeErrorKind::OutOfBounds)
+            }
+            MemoryModel::Dense | MemoryModel::Paged if address as usize >= MAX_DENSE_MEMORY => {
+                Err(IntCodeErrorKind::OutOfBounds)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Reads without counting towards the high-water mark, for inspection
+    // from outside the running program.
+    #[inline]
+    pub fn get(&self, address: i64) -> Result<W, IntCodeErrorKind> {
+        if (address as u64) < (self.dense.len() as u64) {
+            Ok(self.dense[address as usize].clone())
+        } else {
+            self.check_slow(address)?;
+            Ok(self.get_slow(address))
+        }
+    }
+
+    // Address must be valid and beyond dense memory
+    fn get_slow(&self, address: i64) -> W {
+        let value = match self.model {
+            MemoryModel::Paged => self
+                .pages
+                .get(address as usize / PAGE_SIZE)
+                .map(|page| &page[address as usize % PAGE_SIZE]),
+            _ => self.sparse.get(&address),
+        };
+        value.cloned().unwrap_or_else(|| W::from_i64(0))
+    }
+
+    #[inline]
+    pub fn load(&mut self, address: i64) -> Result<W, IntCodeErrorKind> {
+        match self.dense.get(address as usize) {
+            Some(value) => Ok(value.clone()),
+            None => self.load_slow(address),
+        }
+    }
+
+    #[cold]
+    fn load_slow(&mut self, address: i64) -> Result<W, IntCodeErrorKind> {
+        self.check_slow(address)?;
+        self.high_water_mark = self.high_water_mark.max(address as usize + 1);
+        Ok(self.get_slow(address))
+    }
+
+    // Caller must have validated the address with check()
+    #[inline]
+    pub fn store(&mut self, address: i64, value: W) {
+        if (address as u64) < (self.dense.len() as u64) {
+            self.dense[address as usize] = value;
+        } else {
+            self.store_slow(address, value);
+        }
+    }
+
+    #[cold]
+    fn store_slow(&mut self, address: i64, value: W) {
+        debug_assert!(self.check(address).is_ok());
+        self.high_water_mark = self.high_water_mark.max(address as usize + 1);
+        match self.model {
+            MemoryModel::Strict(_) | MemoryModel::Dense => {
+                self.dense.resize(address as usize + 1, W::from_i64(0));
+                self.dense[address as usize] = value;
+            }
+            MemoryModel::Sparse => {
+                self.sparse.insert(address, value);
+            }
+            MemoryModel::Paged => {
+                let idx = address as usize / PAGE_SIZE;
+                if idx >= self.pages.len() {
+                    self.pages.resize(idx + 1, Arc::new(empty_page()));
+                }
+                Arc::make_mut(&mut self.pages[idx])[address as usize % PAGE_SIZE] = value;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strict_memory_is_bounded() {
+        let mut mem: Memory = Memory::new(vec![1, 2, 3], MemoryModel::Strict(8));
+        assert_eq!(mem.load(7), Ok(0));
+        assert_eq!(mem.load(8), Err(IntCodeErrorKind::OutOfBounds));
+        assert_eq!(mem.check(8), Err(IntCodeErrorKind::OutOfBounds));
+        assert_eq!(mem.check(-1), Err(IntCodeErrorKind::NegativeAddress));
+    }
+
+    #[test]
+    fn test_dense_memory_grows() {
+        let mut mem: Memory = Memory::new(vec![1, 2, 3], MemoryModel::Dense);
+        mem.store(100, 5);
+        assert_eq!(mem.load(100), Ok(5));
+        assert_eq!(mem.load(50), Ok(0));
+        assert!(mem.sparse.is_empty());
+        assert_eq!(mem.high_water_mark(), 101);
+    }
+
+    #[test]
+    fn test_sparse_memory_does_not_grow() {
+        let mut mem: Memory = Memory::new(vec![1, 2, 3], MemoryModel::Sparse);
+        mem.store(1 << 40, 5);
+        assert_eq!(mem.load(1 << 40), Ok(5));
+        assert_eq!(mem.dense.len(), 3);
+        assert_eq!(mem.high_water_mark(), (1 << 40) + 1);
+    }
+
+    #[test]
+    fn test_paged_memory_copies_on_write() {
+        let program = (0..1000).collect::<Vec<i64>>();
+        let mut parent = Memory::new(program, MemoryModel::Paged);
+        let mut child = parent.clone();
+        child.store(3, -1);
+        child.store(5000, 7);
+        parent.store(999, 0);
+
+        assert_eq!(parent.load(3), Ok(3));
+        assert_eq!(child.load(3), Ok(-1));
+        assert_eq!(child.load(999), Ok(999));
+        assert_eq!(parent.load(5000), Ok(0));
+        assert_eq!(child.load(5000), Ok(7));
+        assert!(Arc::ptr_eq(&parent.pages[1], &child.pages[1]));
+        assert!(!Arc::ptr_eq(&parent.pages[0], &child.pages[0]));
+        assert!(parent.dense.is_empty());
+        assert_eq!(child.contiguous().len(), 5001);
+    }
+
+    #[test]
+    fn test_get_does_not_move_high_water_mark() {
+        let mem: Memory = Memory::new(vec![1, 2, 3], MemoryModel::Dense);
+        assert_eq!(mem.get(1000), Ok(0));
+        assert_eq!(mem.high_water_mark(), 3);
+    }
+}
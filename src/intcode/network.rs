@@ -0,0 +1,542 @@
+use super::{IntCodeError, IntCodeState};
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+
+// Runs a network of Intcode machines which exchange packets, as in day 23.
+//
+// Each node is told its address as its first input. Nodes take turns in
+// address order, each running until it asks for input while its queue is
+// empty, which it is told with an "empty" value (-1 by default). After each
+// turn, whatever the node output is split into packets of an address
+// followed by a fixed size payload, and these are routed straight away, so
+// later nodes see them in the same round.
+//
+// Addresses of nodes route to the node; others can be routed to a node, or
+// to an Endpoint such as day 23's NAT. The network is idle when every queue
+// has been empty, and no node part way through outputting a packet, at the
+// end of a number of rounds in a row, when endpoints are told so they can
+// wake it up again.
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Packet {
+    pub source: i64,
+    pub address: i64,
+    pub payload: Vec<i64>,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum Action {
+    Continue,
+    Send { address: i64, payload: Vec<i64> },
+    // Stops the network, with a result for run to return
+    Stop(i64),
+}
+
+// Something other than a node which packets can be sent to
+pub trait Endpoint {
+    fn receive(&mut self, packet: &Packet) -> Action;
+
+    fn idle(&mut self) -> Action {
+        Action::Continue
+    }
+}
+
+enum Destination {
+    Node(usize),
+    Endpoint(Box<dyn Endpoint>),
+}
+
+#[derive(Debug)]
+pub enum NetworkEvent<'a> {
+    Sent(&'a Packet),
+    Idle { round: u64 },
+    Halted { node: usize },
+}
+
+#[derive(Debug)]
+pub enum NetworkError {
+    IntCode { node: usize, error: IntCodeError },
+    UnknownAddress(Packet),
+    WrongPayloadSize { packet: Packet, expected: usize },
+    // Every node has halted without an endpoint stopping the network
+    AllHalted,
+    OutOfRounds,
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::IntCode { node, error } => write!(f, "node {}: {}", node, error),
+            NetworkError::UnknownAddress(packet) => write!(
+                f,
+                "packet from {} to unknown address {}",
+                packet.source, packet.address
+            ),
+            NetworkError::WrongPayloadSize { packet, expected } => write!(
+                f,
+                "packet from {} to {} has {} values, expected {}",
+                packet.source,
+                packet.address,
+                packet.payload.len(),
+                expected
+            ),
+            NetworkError::AllHalted => write!(f, "all nodes halted"),
+            NetworkError::OutOfRounds => write!(f, "network still running after round limit"),
+        }
+    }
+}
+
+impl Error for NetworkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NetworkError::IntCode { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+type Observer = Box<dyn FnMut(&NetworkEvent)>;
+
+pub struct Network {
+    nodes: Vec<IntCodeState>,
+    halted: Vec<bool>,
+    queues: Vec<VecDeque<i64>>,
+    routes: BTreeMap<i64, Destination>,
+    observers: Vec<Observer>,
+    payload_size: usize,
+    empty_input: i64,
+    idle_rounds: u32,
+    max_rounds: Option<u64>,
+    rounds: u64,
+}
+
+impl Network {
+    pub fn new(software: &[i64], nodes: usize) -> Self {
+        Network::from_template(&software.into(), nodes)
+    }
+
+    // Nodes start as copies of `template`, e.g. one which has been
+    // pre-decoded or uses another memory model
+    pub fn from_template(template: &IntCodeState, nodes: usize) -> Self {
+        Network {
+            nodes: (0..nodes).map(|_| template.clone()).collect(),
+            halted: vec![false; nodes],
+            queues: (0..nodes as i64).map(|a| VecDeque::from([a])).collect(),
+            routes: (0..nodes)
+                .map(|node| (node as i64, Destination::Node(node)))
+                .collect(),
+            observers: vec![],
+            payload_size: 2,
+            empty_input: -1,
+            idle_rounds: 1,
+            max_rounds: None,
+            rounds: 0,
+        }
+    }
+
+    // Number of values in a packet after the address
+    pub fn with_payload_size(mut self, payload_size: usize) -> Self {
+        self.payload_size = payload_size;
+        self
+    }
+
+    // Input given to a node asking for input when its queue is empty
+    pub fn with_empty_input(mut self, empty_input: i64) -> Self {
+        self.empty_input = empty_input;
+        self
+    }
+
+    // Number of rounds in a row which must end with every queue empty for
+    // the network to count as idle
+    pub fn with_idle_rounds(mut self, idle_rounds: u32) -> Self {
+        self.idle_rounds = idle_rounds.max(1);
+        self
+    }
+
+    // Gives up with an error after this many rounds, counting any already
+    // run
+    pub fn with_max_rounds(mut self, max_rounds: u64) -> Self {
+        self.max_rounds = Some(max_rounds);
+        self
+    }
+
+    // Sends packets for `address` to a node, replacing any existing route
+    pub fn route(&mut self, address: i64, node: usize) {
+        assert!(node < self.nodes.len(), "no node {}", node);
+        self.routes.insert(address, Destination::Node(node));
+    }
+
+    // Sends packets for `address` to an endpoint, replacing any existing
+    // route
+    pub fn connect<E: Endpoint + 'static>(&mut self, address: i64, endpoint: E) {
+        self.routes
+            .insert(address, Destination::Endpoint(Box::new(endpoint)));
+    }
+
+    pub fn observe<F: FnMut(&NetworkEvent) + 'static>(&mut self, observer: F) {
+        self.observers.push(Box::new(observer));
+    }
+
+    pub fn nodes(&self) -> &[IntCodeState] {
+        &self.nodes
+    }
+
+    pub fn rounds(&self) -> u64 {
+        self.rounds
+    }
+
+    fn notify(&mut self, event: NetworkEvent) {
+        for observer in self.observers.iter_mut() {
+            observer(&event);
+        }
+    }
+
+    // Packets endpoints send in reply are delivered in turn, rather than
+    // recursing, so endpoints can pass packets between each other freely
+    fn deliver(&mut self, packet: Packet) -> Result<Option<i64>, NetworkError> {
+        let mut pending = VecDeque::from([packet]);
+        while let Some(packet) = pending.pop_front() {
+            if packet.payload.len() != self.payload_size {
+                return Err(NetworkError::WrongPayloadSize {
+                    packet,
+                    expected: self.payload_size,
+                });
+            }
+            self.notify(NetworkEvent::Sent(&packet));
+            let action = match self.routes.get_mut(&packet.address) {
+                Some(Destination::Node(node)) => {
+                    self.queues[*node].extend(packet.payload.iter().copied());
+                    Action::Continue
+                }
+                Some(Destination::Endpoint(endpoint)) => endpoint.receive(&packet),
+                None => return Err(NetworkError::UnknownAddress(packet)),
+            };
+            match action {
+                Action::Continue => {}
+                Action::Send { address, payload } => pending.push_back(Packet {
+                    source: packet.address,
+                    address,
+                    payload,
+                }),
+                Action::Stop(result) => return Ok(Some(result)),
+            }
+        }
+        Ok(None)
+    }
+
+    fn act(&mut self, source: i64, action: Action) -> Result<Option<i64>, NetworkError> {
+        match action {
+            Action::Continue => Ok(None),
+            Action::Send { address, payload } => self.deliver(Packet {
+                source,
+                address,
+                payload,
+            }),
+            Action::Stop(result) => Ok(Some(result)),
+        }
+    }
+
+    // Runs the node until it's told its queue is empty, then sends whatever
+    // it output
+    fn turn(&mut self, node: usize) -> Result<Option<i64>, NetworkError> {
+        let (nic, queue) = (&mut self.nodes[node], &mut self.queues[node]);
+        let empty_input = self.empty_input;
+        let mut told_empty = false;
+        let mut halted = false;
+        while !told_empty && !halted {
+            halted = nic
+                .try_execute_single_step(|_| {
+                    queue.pop_front().or_else(|| {
+                        told_empty = true;
+                        Some(empty_input)
+                    })
+                })
+                .map_err(|error| NetworkError::IntCode { node, error })?;
+        }
+        if halted {
+            self.halted[node] = true;
+            self.notify(NetworkEvent::Halted { node });
+        }
+
+        let packet_size = self.payload_size + 1;
+        while self.nodes[node].out_buffer.len() >= packet_size {
+            let mut values = self.nodes[node].out_buffer.drain(..packet_size);
+            let address = values.next().expect("packet has an address");
+            let packet = Packet {
+                source: node as i64,
+                address,
+                payload: values.collect(),
+            };
+            if let Some(result) = self.deliver(packet)? {
+                return Ok(Some(result));
+            }
+        }
+        Ok(None)
+    }
+
+    // Runs until an endpoint stops the network, returning its result
+    pub fn run(&mut self) -> Result<i64, NetworkError> {
+        let mut idle_for = 0;
+        loop {
+            if self.max_rounds.is_some_and(|max| self.rounds >= max) {
+                return Err(NetworkError::OutOfRounds);
+            }
+            for node in 0..self.nodes.len() {
+                if self.halted[node] {
+                    continue;
+                }
+                if let Some(result) = self.turn(node)? {
+                    return Ok(result);
+                }
+            }
+            self.rounds += 1;
+            if self.halted.iter().all(|&h| h) {
+                return Err(NetworkError::AllHalted);
+            }
+
+            // Whole packets have all been sent, so any output left is part
+            // of one still being written
+            let busy = self.queues.iter().any(|q| !q.is_empty())
+                || self.nodes.iter().any(|n| !n.out_buffer.is_empty());
+            if busy {
+                idle_for = 0;
+                continue;
+            }
+            idle_for += 1;
+            if idle_for < self.idle_rounds {
+                continue;
+            }
+            idle_for = 0;
+            self.notify(NetworkEvent::Idle { round: self.rounds });
+            let addresses = self
+                .routes
+                .iter()
+                .filter(|(_, dest)| matches!(dest, Destination::Endpoint(_)))
+                .map(|(&address, _)| address)
+                .collect::<Vec<_>>();
+            for address in addresses {
+                let Some(Destination::Endpoint(endpoint)) = self.routes.get_mut(&address) else {
+                    continue;
+                };
+                let action = endpoint.idle();
+                if let Some(result) = self.act(address, action)? {
+                    return Ok(result);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Node 0 starts a packet (0, hops) round a ring of `size` nodes. Each
+    // node passes it on with x one more and hops one less, sending it to
+    // `exit` once it runs out of hops.
+    fn ring(size: i64, hops: i64, exit: i64) -> Vec<i64> {
+        assemble(&format!(
+            "
+                    in addr
+                    jt addr, #wait
+                    out #1
+                    out #0
+                    out #{hops}
+            wait:   in x
+                    eq x, #-1, t
+                    jt t, #wait
+                    in y
+                    jf y, #done
+                    add addr, #1, dest
+                    eq dest, #{size}, t
+                    jf t, #send
+                    add #0, #0, dest
+            send:   out dest
+                    add x, #1, x
+                    out x
+                    add y, #-1, y
+                    out y
+                    jt #1, #wait
+            done:   out #{exit}
+                    out x
+                    out y
+                    jt #1, #wait
+            addr:   data 0
+            x:      data 0
+            y:      data 0
+            t:      data 0
+            dest:   data 0
+            "
+        ))
+        .unwrap()
+    }
+
+    struct Exit;
+
+    impl Endpoint for Exit {
+        fn receive(&mut self, packet: &Packet) -> Action {
+            Action::Stop(packet.payload[0])
+        }
+    }
+
+    // Starts another lap whenever the network goes idle, stopping after
+    // `laps` of them
+    struct Restart {
+        laps: i64,
+        payload: Vec<i64>,
+    }
+
+    impl Endpoint for Restart {
+        fn receive(&mut self, _: &Packet) -> Action {
+            Action::Continue
+        }
+
+        fn idle(&mut self) -> Action {
+            self.laps -= 1;
+            if self.laps == 0 {
+                return Action::Stop(0);
+            }
+            Action::Send {
+                address: 0,
+                payload: self.payload.clone(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_ring() {
+        let mut network = Network::new(&ring(5, 12, 255), 5);
+        network.connect(255, Exit);
+        let sent = Rc::new(RefCell::new(vec![]));
+        let log = sent.clone();
+        network.observe(move |event| {
+            if let NetworkEvent::Sent(packet) = event {
+                log.borrow_mut().push((packet.source, packet.address));
+            }
+        });
+
+        assert_eq!(network.run().unwrap(), 12);
+        let sent = sent.borrow();
+        assert_eq!(sent.len(), 14);
+        assert_eq!(sent[..6], [(0, 1), (1, 2), (2, 3), (3, 4), (4, 0), (0, 1)]);
+        assert_eq!(sent[13], (3, 255));
+        // Packets are passed on in the same round where possible, so this
+        // stops part way through the third
+        assert_eq!(network.rounds(), 2);
+    }
+
+    #[test]
+    fn test_idle() {
+        let mut network = Network::new(&ring(3, 2, 7), 3).with_idle_rounds(2);
+        // Leaves node 2 out of the ring
+        network.route(2, 0);
+        network.connect(
+            7,
+            Restart {
+                laps: 3,
+                payload: vec![0, 3],
+            },
+        );
+        let idle = Rc::new(RefCell::new(vec![]));
+        let log = idle.clone();
+        network.observe(move |event| {
+            if let NetworkEvent::Idle { round } = event {
+                log.borrow_mut().push(*round);
+            }
+        });
+
+        assert_eq!(network.run().unwrap(), 0);
+        assert_eq!(*idle.borrow(), vec![3, 6, 9]);
+    }
+
+    // Stops with the first value it's sent, or -1 if the network goes idle
+    // first
+    struct FirstOrIdle;
+
+    impl Endpoint for FirstOrIdle {
+        fn receive(&mut self, packet: &Packet) -> Action {
+            Action::Stop(packet.payload[0])
+        }
+
+        fn idle(&mut self) -> Action {
+            Action::Stop(-1)
+        }
+    }
+
+    // Passes every packet on to `to`
+    struct Forward {
+        to: i64,
+    }
+
+    impl Endpoint for Forward {
+        fn receive(&mut self, packet: &Packet) -> Action {
+            Action::Send {
+                address: self.to,
+                payload: packet.payload.clone(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_partial_packet_not_idle() {
+        // Outputs the address of a packet, then waits a round before
+        // finishing it
+        let program = assemble(
+            "
+                    out #5
+                    in t
+                    in t
+                    out #7
+                    out #8
+            loop:   in t
+                    jt #1, #loop
+            t:      data 0
+            ",
+        )
+        .unwrap();
+        let mut network = Network::new(&program, 1);
+        network.connect(5, Forward { to: 6 });
+        network.connect(6, FirstOrIdle);
+        assert_eq!(network.run().unwrap(), 7);
+        // Stopped part way through the second round
+        assert_eq!(network.rounds(), 1);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut network = Network::new(&ring(3, 2, 7), 3);
+        match network.run() {
+            Err(NetworkError::UnknownAddress(packet)) => {
+                assert_eq!(packet.address, 7);
+                assert_eq!(packet.payload, vec![2, 0]);
+            }
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+
+        let mut network = Network::new(&ring(3, 2, 7), 3);
+        network.connect(
+            7,
+            Restart {
+                laps: 2,
+                payload: vec![0],
+            },
+        );
+        assert!(matches!(
+            network.run(),
+            Err(NetworkError::WrongPayloadSize { expected: 2, .. })
+        ));
+
+        // Node 0's first packet is too short to ever be sent
+        let mut network = Network::new(&ring(3, 2, 7), 3)
+            .with_payload_size(3)
+            .with_max_rounds(100);
+        assert!(matches!(network.run(), Err(NetworkError::OutOfRounds)));
+
+        let mut network = Network::new(&[3, 10, 99], 2);
+        assert!(matches!(network.run(), Err(NetworkError::AllHalted)));
+    }
+}
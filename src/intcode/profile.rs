@@ -0,0 +1,221 @@
+use super::disasm::Opcode;
+use super::IntCodeState;
+use ahash::AHashMap;
+use std::fmt::Write;
+
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct Profile {
+    steps: u64,
+    by_address: AHashMap<i64, u64>,
+    by_opcode: AHashMap<Opcode, u64>,
+    // Taken jumps to an address at or before the jump, keyed by (from, to)
+    back_edges: AHashMap<(i64, i64), u64>,
+}
+
+// A range of addresses which a backward jump keeps returning to the start of
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct HotLoop {
+    pub start: i64,
+    pub end: i64,
+    pub iterations: u64,
+    // Instructions executed within start..=end, including those from other
+    // loops nested inside it
+    pub steps: u64,
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        100.0 * count as f64 / total as f64
+    }
+}
+
+impl Profile {
+    pub(super) fn record(&mut self, from: i64, opcode: Opcode, to: i64) {
+        self.steps += 1;
+        *self.by_address.entry(from).or_default() += 1;
+        *self.by_opcode.entry(opcode).or_default() += 1;
+        if to <= from && matches!(opcode, Opcode::JumpIfTrue | Opcode::JumpIfFalse) {
+            *self.back_edges.entry((from, to)).or_default() += 1;
+        }
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn count_at(&self, address: i64) -> u64 {
+        self.by_address.get(&address).copied().unwrap_or(0)
+    }
+
+    pub fn count_of(&self, opcode: Opcode) -> u64 {
+        self.by_opcode.get(&opcode).copied().unwrap_or(0)
+    }
+
+    // Most executed first, ties by address
+    pub fn hot_addresses(&self) -> Vec<(i64, u64)> {
+        let mut addresses = self
+            .by_address
+            .iter()
+            .map(|(&a, &c)| (a, c))
+            .collect::<Vec<_>>();
+        addresses.sort_by_key(|&(a, c)| (std::cmp::Reverse(c), a));
+        addresses
+    }
+
+    pub fn opcodes(&self) -> Vec<(Opcode, u64)> {
+        let mut opcodes = self
+            .by_opcode
+            .iter()
+            .map(|(&op, &c)| (op, c))
+            .collect::<Vec<_>>();
+        opcodes.sort_by_key(|&(op, c)| (std::cmp::Reverse(c), op.code()));
+        opcodes
+    }
+
+    pub fn hot_loops(&self) -> Vec<HotLoop> {
+        let mut loops = self
+            .back_edges
+            .iter()
+            .map(|(&(end, start), &iterations)| HotLoop {
+                start,
+                end,
+                iterations,
+                steps: self
+                    .by_address
+                    .iter()
+                    .filter(|(a, _)| (start..=end).contains(*a))
+                    .map(|(_, c)| c)
+                    .sum(),
+            })
+            .collect::<Vec<_>>();
+        loops.sort_by_key(|l| (std::cmp::Reverse(l.steps), l.start, l.end));
+        loops
+    }
+
+    // Shows at most `limit` entries in each section. Instructions are
+    // disassembled from prog's current memory.
+    pub fn report(&self, prog: &IntCodeState, limit: usize) -> String {
+        let mut out = String::new();
+        writeln!(out, "{} instructions executed", self.steps).unwrap();
+
+        writeln!(out, "\nby opcode:").unwrap();
+        for (opcode, count) in self.opcodes() {
+            writeln!(
+                out,
+                "  {:<4} {:>12} {:>6.2}%",
+                opcode.to_string(),
+                count,
+                percent(count, self.steps)
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\nhot addresses:").unwrap();
+        for (address, count) in self.hot_addresses().into_iter().take(limit) {
+            let instruction = prog
+                .decode_executable_at(address)
+                .map(|ins| ins.to_string())
+                .unwrap_or_else(|| "???".to_string());
+            writeln!(
+                out,
+                "  {:>5}: {:>12} {:>6.2}%  {}",
+                address,
+                count,
+                percent(count, self.steps),
+                instruction
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\nhot loops:").unwrap();
+        for l in self.hot_loops().into_iter().take(limit) {
+            writeln!(
+                out,
+                "  {:>5}..{:<5} {:>10} iterations {:>12} instructions {:>6.2}%",
+                l.start,
+                l.end,
+                l.iterations,
+                l.steps,
+                percent(l.steps, self.steps)
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+impl IntCodeState {
+    // Restarts counting from zero if already profiling
+    pub fn start_profile(&mut self) {
+        self.instruments.get_mut().profile = Some(Profile::default());
+    }
+
+    pub fn stop_profile(&mut self) -> Option<Profile> {
+        let profile = self.instruments.get_mut().profile.take();
+        self.instruments.prune();
+        profile
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.instruments.get().and_then(|i| i.profile.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counts memory[20] down from 3 to 0, outputting each value
+    const COUNTDOWN: [i64; 21] = [
+        4, 20, 1001, 20, -1, 20, 1005, 20, 0, 99, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3,
+    ];
+
+    #[test]
+    fn test_profile_counts() {
+        let mut prog: IntCodeState = COUNTDOWN.as_slice().into();
+        prog.start_profile();
+        prog.execute_until_halt_no_input();
+        let profile = prog.stop_profile().unwrap();
+
+        assert_eq!(profile.steps(), 10);
+        assert_eq!(profile.count_at(0), 3);
+        assert_eq!(profile.count_at(9), 1);
+        assert_eq!(profile.count_of(Opcode::Out), 3);
+        assert_eq!(profile.count_of(Opcode::Mul), 0);
+        assert_eq!(profile.hot_addresses()[..2], [(0, 3), (2, 3)]);
+        assert_eq!(
+            profile.hot_loops(),
+            vec![HotLoop {
+                start: 0,
+                end: 6,
+                iterations: 2,
+                steps: 9
+            }]
+        );
+        assert!(prog.profile().is_none());
+    }
+
+    #[test]
+    fn test_blocked_input_not_counted() {
+        let mut prog: IntCodeState = vec![3, 0, 99].into();
+        prog.start_profile();
+        prog.run_until_event();
+        assert_eq!(prog.profile().unwrap().steps(), 0);
+        prog.in_buffer.push_back(1);
+        prog.run_until_event();
+        assert_eq!(prog.profile().unwrap().count_of(Opcode::In), 1);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut prog: IntCodeState = COUNTDOWN.as_slice().into();
+        prog.start_profile();
+        prog.execute_until_halt_no_input();
+        let report = prog.profile().unwrap().report(&prog, 1);
+        assert!(report.starts_with("10 instructions executed\n"));
+        assert!(report.contains("      0:            3  30.00%  out 20\n"));
+        assert!(report.contains("      0..6              2 iterations"));
+    }
+}
@@ -0,0 +1,348 @@
+use super::dispatch::HandlerCache;
+use super::memory::Memory;
+use super::{IntCodeState, MemoryModel};
+use ahash::AHashMap;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+// Snapshots are plain text, one field per line, in this order:
+//
+//   intcode-snapshot 1
+//   ip 1234
+//   base 2048
+//   model dense
+//   high_water 2100
+//   memory 3
+//   109,1,204
+//   sparse 2
+//   100000:5,100001:7
+//   in 0
+//
+//   out 1
+//   42
+//
+// The model is `dense`, `sparse`, `paged` or `strict <size>`. Lists give their length,
+// then their items comma separated on the next line, which is empty for an
+// empty list. Sparse memory is listed as address:value pairs. `in` and `out`
+// are the pending input and output buffers. The version is bumped whenever
+// the format changes.
+
+const MAGIC: &str = "intcode-snapshot";
+const VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    UnsupportedVersion(String),
+    Format { line: usize, message: String },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "can't read snapshot: {}", e),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "unsupported snapshot version {}", v)
+            }
+            SnapshotError::Format { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SnapshotError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+fn write_list<W: Write, T: fmt::Display>(
+    writer: &mut W,
+    name: &str,
+    items: &[T],
+) -> io::Result<()> {
+    writeln!(writer, "{} {}", name, items.len())?;
+    for (idx, item) in items.iter().enumerate() {
+        if idx > 0 {
+            write!(writer, ",")?;
+        }
+        write!(writer, "{}", item)?;
+    }
+    writeln!(writer)
+}
+
+struct Parser<'a> {
+    lines: std::iter::Enumerate<std::str::Lines<'a>>,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, message: String) -> Result<T, SnapshotError> {
+        Err(SnapshotError::Format {
+            line: self.line,
+            message,
+        })
+    }
+
+    fn next_line(&mut self) -> Result<&'a str, SnapshotError> {
+        match self.lines.next() {
+            Some((idx, line)) => {
+                self.line = idx + 1;
+                Ok(line)
+            }
+            None => {
+                self.line += 1;
+                self.error("unexpected end of snapshot".to_string())
+            }
+        }
+    }
+
+    fn parse<T: FromStr>(&self, value: &str) -> Result<T, SnapshotError> {
+        value
+            .parse()
+            .or_else(|_| self.error(format!("invalid number '{}'", value)))
+    }
+
+    // Returns the rest of a `name ...` line
+    fn field(&mut self, name: &str) -> Result<&'a str, SnapshotError> {
+        let line = self.next_line()?;
+        match line.split_once(' ') {
+            Some((key, rest)) if key == name => Ok(rest),
+            _ => self.error(format!("expected '{}'", name)),
+        }
+    }
+
+    fn number<T: FromStr>(&mut self, name: &str) -> Result<T, SnapshotError> {
+        let value = self.field(name)?;
+        self.parse(value)
+    }
+
+    fn list<T, F>(&mut self, name: &str, mut parse_item: F) -> Result<Vec<T>, SnapshotError>
+    where
+        F: FnMut(&Self, &str) -> Result<T, SnapshotError>,
+    {
+        let len: usize = self.number(name)?;
+        let line = self.next_line()?;
+        let items = if line.is_empty() {
+            vec![]
+        } else {
+            line.split(',')
+                .map(|item| parse_item(self, item))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        if items.len() != len {
+            return self.error(format!("expected {} items, found {}", len, items.len()));
+        }
+        Ok(items)
+    }
+
+    fn model(&mut self) -> Result<MemoryModel, SnapshotError> {
+        let value = self.field("model")?;
+        match value.split_once(' ') {
+            _ if value == "dense" => Ok(MemoryModel::Dense),
+            _ if value == "sparse" => Ok(MemoryModel::Sparse),
+            _ if value == "paged" => Ok(MemoryModel::Paged),
+            Some(("strict", size)) => Ok(MemoryModel::Strict(self.parse(size)?)),
+            _ => self.error(format!("unknown memory model '{}'", value)),
+        }
+    }
+}
+
+impl IntCodeState {
+    // Instrumentation such as tracing and profiling is not saved.
+    pub fn write_snapshot<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "{} {}", MAGIC, VERSION)?;
+        writeln!(writer, "ip {}", self.instruction_ptr)?;
+        writeln!(writer, "base {}", self.base_ptr)?;
+        match self.memory.model() {
+            MemoryModel::Strict(size) => writeln!(writer, "model strict {}", size)?,
+            MemoryModel::Dense => writeln!(writer, "model dense")?,
+            MemoryModel::Sparse => writeln!(writer, "model sparse")?,
+            MemoryModel::Paged => writeln!(writer, "model paged")?,
+        }
+        writeln!(writer, "high_water {}", self.memory.high_water_mark())?;
+        write_list(&mut writer, "memory", &self.memory.contiguous())?;
+
+        let mut sparse = self.memory.sparse().iter().collect::<Vec<_>>();
+        sparse.sort();
+        let sparse = sparse
+            .iter()
+            .map(|(a, v)| format!("{}:{}", a, v))
+            .collect::<Vec<_>>();
+        write_list(&mut writer, "sparse", &sparse)?;
+
+        write_list(
+            &mut writer,
+            "in",
+            &self.in_buffer.iter().collect::<Vec<_>>(),
+        )?;
+        write_list(
+            &mut writer,
+            "out",
+            &self.out_buffer.iter().collect::<Vec<_>>(),
+        )?;
+        writer.flush()
+    }
+
+    pub fn read_snapshot<R: Read>(mut reader: R) -> Result<IntCodeState, SnapshotError> {
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+        let mut parser = Parser {
+            lines: text.lines().enumerate(),
+            line: 0,
+        };
+
+        let version = parser.field(MAGIC)?;
+        if version.parse() != Ok(VERSION) {
+            return Err(SnapshotError::UnsupportedVersion(version.to_string()));
+        }
+
+        let instruction_ptr = parser.number("ip")?;
+        let base_ptr = parser.number("base")?;
+        let model = parser.model()?;
+        let high_water_mark: usize = parser.number("high_water")?;
+        let dense = parser.list("memory", |p, v| p.parse(v))?;
+        if high_water_mark < dense.len() {
+            return parser.error("high water mark is below memory size".to_string());
+        }
+
+        let sparse = parser.list("sparse", |p, item| {
+            let Some((address, value)) = item.split_once(':') else {
+                return p.error(format!("expected address:value, found '{}'", item));
+            };
+            let address: i64 = p.parse(address)?;
+            if address < dense.len() as i64 || address as u64 >= high_water_mark as u64 {
+                return p.error(format!("sparse address {} out of range", address));
+            }
+            Ok((address, p.parse(value)?))
+        })?;
+        if !sparse.is_empty() && model != MemoryModel::Sparse {
+            return parser.error("sparse memory in a non-sparse model".to_string());
+        }
+
+        let in_buffer = parser.list("in", |p, v| p.parse(v))?;
+        let out_buffer = parser.list("out", |p, v| p.parse(v))?;
+
+        let mut state = IntCodeState::new(vec![], model);
+        state.handlers = HandlerCache::new(dense.len());
+        state.instruction_ptr = instruction_ptr;
+        state.base_ptr = base_ptr;
+        state.memory = Memory::from_parts(
+            model,
+            dense,
+            sparse.into_iter().collect::<AHashMap<_, _>>(),
+            high_water_mark,
+        );
+        state.in_buffer = VecDeque::from(in_buffer);
+        state.out_buffer = VecDeque::from(out_buffer);
+        Ok(state)
+    }
+
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.write_snapshot(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn load_snapshot<P: AsRef<Path>>(path: P) -> Result<IntCodeState, SnapshotError> {
+        IntCodeState::read_snapshot(File::open(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::IntCodeEvent;
+    use super::*;
+
+    fn round_trip(prog: &IntCodeState) -> IntCodeState {
+        let mut bytes = vec![];
+        prog.write_snapshot(&mut bytes).unwrap();
+        IntCodeState::read_snapshot(bytes.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_resume_from_snapshot() {
+        // Echoes input plus one, forever
+        let program = vec![3, 11, 1001, 11, 1, 11, 4, 11, 1105, 1, 0, 0];
+        let mut prog = IntCodeState::new(program, MemoryModel::Sparse);
+        prog.in_buffer.extend([1, 2]);
+        prog.run_until_event();
+        prog.set_mem(1 << 40, 9);
+        prog.out_buffer.push_back(-5);
+
+        let mut restored = round_trip(&prog);
+        assert_eq!(restored, prog);
+        assert_eq!(restored.run_until_event(), prog.run_until_event());
+        assert_eq!(restored.out_buffer, VecDeque::from([-5]));
+        assert_eq!(restored.get_mem(1 << 40), 9);
+    }
+
+    #[test]
+    fn test_wrapped_input_buffer() {
+        let mut prog: IntCodeState = vec![3, 0, 3, 0, 3, 0, 4, 0, 99].into();
+        prog.in_buffer = VecDeque::with_capacity(4);
+        prog.in_buffer.extend([1, 2, 3, 4]);
+        prog.in_buffer.pop_front();
+        prog.in_buffer.pop_front();
+        prog.in_buffer.extend([5, 6]);
+        assert!(!prog.in_buffer.as_slices().1.is_empty());
+
+        let mut restored = round_trip(&prog);
+        assert_eq!(restored.in_buffer, VecDeque::from([3, 4, 5, 6]));
+        assert_eq!(restored.run_until_event(), IntCodeEvent::Output(5));
+    }
+
+    #[test]
+    fn test_paged_snapshot() {
+        let mut prog = IntCodeState::new(vec![1101, 1, 2, 1000, 99], MemoryModel::Paged);
+        prog.execute_until_halt_no_input();
+        assert_eq!(round_trip(&prog), prog);
+    }
+
+    #[test]
+    fn test_snapshot_format() {
+        let mut prog = IntCodeState::new(vec![109, 3, 99], MemoryModel::Strict(16));
+        prog.execute_until_halt_no_input();
+        let mut bytes = vec![];
+        prog.write_snapshot(&mut bytes).unwrap();
+        assert_eq!(
+            String::from_utf8(bytes).unwrap(),
+            "intcode-snapshot 1\nip 2\nbase 3\nmodel strict 16\nhigh_water 3\n\
+             memory 3\n109,3,99\nsparse 0\n\nin 0\n\nout 0\n\n"
+        );
+        assert_eq!(round_trip(&prog), prog);
+    }
+
+    #[test]
+    fn test_bad_snapshots() {
+        let read = |s: &str| IntCodeState::read_snapshot(s.as_bytes()).unwrap_err();
+        assert!(matches!(
+            read("intcode-snapshot 2\n"),
+            SnapshotError::UnsupportedVersion(v) if v == "2"
+        ));
+        assert_eq!(
+            read("intcode-snapshot 1\nip x\n").to_string(),
+            "line 2: invalid number 'x'"
+        );
+        assert_eq!(
+            read("intcode-snapshot 1\nip 0\nbase 0\nmodel dense\nhigh_water 3\nmemory 3\n1,2\n")
+                .to_string(),
+            "line 7: expected 3 items, found 2"
+        );
+        assert_eq!(
+            read("intcode-snapshot 1\nip 0\n").to_string(),
+            "line 3: unexpected end of snapshot"
+        );
+    }
+}
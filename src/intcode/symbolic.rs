@@ -0,0 +1,650 @@
+use super::{Instruction, IntCodeErrorKind};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+// Runs a program with some memory cells or inputs left as variables, so
+// that values are expressions in those variables rather than numbers.
+// Where a jump depends on a variable, both ways are explored, each with
+// the condition it relies on, so every path through the program comes out
+// with its outputs and memory as formulas of the variables.
+//
+// Addresses, jump targets and instructions themselves must work out to
+// numbers. Reading through an address which doesn't gives an opaque Load,
+// as the value could come from anywhere; writing through one, or jumping to
+// one, ends the path. Memory is unbounded, as with MemoryModel::Sparse, and
+// arithmetic wraps, as in a release build of IntCodeState.
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum Var {
+    // Value the cell held when the program started
+    Memory(i64),
+    // The nth input read, counting from 0
+    Input(usize),
+}
+
+impl fmt::Display for Var {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Var::Memory(address) => write!(f, "mem_{}", address),
+            Var::Input(n) => write!(f, "in_{}", n),
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Hash)]
+pub enum Expr {
+    Const(i64),
+    Var(Var),
+    Add(Arc<Expr>, Arc<Expr>),
+    Mul(Arc<Expr>, Arc<Expr>),
+    LessThan(Arc<Expr>, Arc<Expr>),
+    Equals(Arc<Expr>, Arc<Expr>),
+    // Whatever was in memory at a symbolic address when it was read
+    Load(Arc<Expr>),
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Const(value) => write!(f, "{}", value),
+            Expr::Var(var) => write!(f, "{}", var),
+            Expr::Add(x, y) => write!(f, "({} + {})", x, y),
+            Expr::Mul(x, y) => write!(f, "{} * {}", Factor(x), Factor(y)),
+            Expr::LessThan(x, y) => write!(f, "({} < {})", x, y),
+            Expr::Equals(x, y) => write!(f, "({} == {})", x, y),
+            Expr::Load(address) => write!(f, "mem[{}]", address),
+        }
+    }
+}
+
+// Products only need brackets where they're nested in each other
+struct Factor<'a>(&'a Expr);
+
+impl fmt::Display for Factor<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Expr::Mul(..) => write!(f, "({})", self.0),
+            expr => write!(f, "{}", expr),
+        }
+    }
+}
+
+// Constant plus a sum of variables times coefficients
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct Linear {
+    pub constant: i64,
+    pub terms: BTreeMap<Var, i64>,
+}
+
+impl Linear {
+    pub fn coefficient(&self, var: Var) -> i64 {
+        self.terms.get(&var).copied().unwrap_or(0)
+    }
+
+    fn add(mut self, other: Linear) -> Linear {
+        self.constant = self.constant.wrapping_add(other.constant);
+        for (var, coefficient) in other.terms {
+            let sum = self.coefficient(var).wrapping_add(coefficient);
+            if sum == 0 {
+                self.terms.remove(&var);
+            } else {
+                self.terms.insert(var, sum);
+            }
+        }
+        self
+    }
+
+    fn scale(mut self, factor: i64) -> Linear {
+        self.constant = self.constant.wrapping_mul(factor);
+        self.terms.retain(|_, coefficient| {
+            *coefficient = coefficient.wrapping_mul(factor);
+            *coefficient != 0
+        });
+        self
+    }
+}
+
+impl Expr {
+    pub fn var(var: Var) -> Arc<Expr> {
+        Arc::new(Expr::Var(var))
+    }
+
+    pub fn constant(value: i64) -> Arc<Expr> {
+        Arc::new(Expr::Const(value))
+    }
+
+    pub fn as_const(&self) -> Option<i64> {
+        match self {
+            Expr::Const(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    // The constructors fold constants, so that expressions built from
+    // numbers alone stay as numbers
+    pub fn sum(x: Arc<Expr>, y: Arc<Expr>) -> Arc<Expr> {
+        match (x.as_const(), y.as_const()) {
+            (Some(a), Some(b)) => Expr::constant(a.wrapping_add(b)),
+            (Some(0), _) => y,
+            (_, Some(0)) => x,
+            _ => Arc::new(Expr::Add(x, y)),
+        }
+    }
+
+    pub fn product(x: Arc<Expr>, y: Arc<Expr>) -> Arc<Expr> {
+        match (x.as_const(), y.as_const()) {
+            (Some(a), Some(b)) => Expr::constant(a.wrapping_mul(b)),
+            (Some(0), _) | (_, Some(0)) => Expr::constant(0),
+            (Some(1), _) => y,
+            (_, Some(1)) => x,
+            _ => Arc::new(Expr::Mul(x, y)),
+        }
+    }
+
+    pub fn less_than(x: Arc<Expr>, y: Arc<Expr>) -> Arc<Expr> {
+        match (x.as_const(), y.as_const()) {
+            (Some(a), Some(b)) => Expr::constant((a < b) as i64),
+            _ if x == y => Expr::constant(0),
+            _ => Arc::new(Expr::LessThan(x, y)),
+        }
+    }
+
+    pub fn equals(x: Arc<Expr>, y: Arc<Expr>) -> Arc<Expr> {
+        match (x.as_const(), y.as_const()) {
+            (Some(a), Some(b)) => Expr::constant((a == b) as i64),
+            _ if x == y => Expr::constant(1),
+            _ => Arc::new(Expr::Equals(x, y)),
+        }
+    }
+
+    // None if a variable has no value, or there's a Load in the way
+    pub fn eval<F>(&self, values: &F) -> Option<i64>
+    where
+        F: Fn(Var) -> Option<i64>,
+    {
+        Some(match self {
+            Expr::Const(value) => *value,
+            Expr::Var(var) => values(*var)?,
+            Expr::Add(x, y) => x.eval(values)?.wrapping_add(y.eval(values)?),
+            Expr::Mul(x, y) => x.eval(values)?.wrapping_mul(y.eval(values)?),
+            Expr::LessThan(x, y) => (x.eval(values)? < y.eval(values)?) as i64,
+            Expr::Equals(x, y) => (x.eval(values)? == y.eval(values)?) as i64,
+            Expr::Load(_) => return None,
+        })
+    }
+
+    // The expression as a linear function of its variables, if it is one
+    pub fn linear(&self) -> Option<Linear> {
+        match self {
+            Expr::Const(value) => Some(Linear {
+                constant: *value,
+                terms: BTreeMap::new(),
+            }),
+            Expr::Var(var) => Some(Linear {
+                constant: 0,
+                terms: BTreeMap::from([(*var, 1)]),
+            }),
+            Expr::Add(x, y) => Some(x.linear()?.add(y.linear()?)),
+            Expr::Mul(x, y) => {
+                let (x, y) = (x.linear()?, y.linear()?);
+                if x.terms.is_empty() {
+                    Some(y.scale(x.constant))
+                } else if y.terms.is_empty() {
+                    Some(x.scale(y.constant))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+// A branch was taken on `expr` being non-zero (`holds`) or zero
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Condition {
+    pub expr: Arc<Expr>,
+    pub holds: bool,
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} 0",
+            self.expr,
+            if self.holds { "!=" } else { "==" }
+        )
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum PathEnd {
+    Halted,
+    // Ran out of the inputs given
+    NeedsInput,
+    OutOfFuel,
+    Fault(IntCodeErrorKind),
+    // An address to write to, jump target or instruction wasn't a number
+    Unresolved(Arc<Expr>),
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Path {
+    pub conditions: Vec<Condition>,
+    pub outputs: Vec<Arc<Expr>>,
+    pub end: PathEnd,
+    pub instruction_ptr: i64,
+    memory: BTreeMap<i64, Arc<Expr>>,
+}
+
+impl Path {
+    pub fn get_mem(&self, address: i64) -> Arc<Expr> {
+        self.memory
+            .get(&address)
+            .cloned()
+            .unwrap_or_else(|| Expr::constant(0))
+    }
+
+    // Whether these values of the variables take this path. None if a
+    // condition can't be evaluated.
+    pub fn is_taken<F>(&self, values: &F) -> Option<bool>
+    where
+        F: Fn(Var) -> Option<i64>,
+    {
+        for condition in self.conditions.iter() {
+            if (condition.expr.eval(values)? != 0) != condition.holds {
+                return Some(false);
+            }
+        }
+        Some(true)
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct SymbolicLimits {
+    // Most instructions to run along any one path
+    pub fuel: u64,
+    // Most paths to return; exploring stops once this many have ended
+    pub max_paths: usize,
+}
+
+impl Default for SymbolicLimits {
+    fn default() -> Self {
+        SymbolicLimits {
+            fuel: 100_000,
+            max_paths: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SymbolicState {
+    memory: BTreeMap<i64, Arc<Expr>>,
+    instruction_ptr: i64,
+    base_ptr: i64,
+    inputs: Vec<Arc<Expr>>,
+    inputs_read: usize,
+    conditions: Vec<Condition>,
+    outputs: Vec<Arc<Expr>>,
+}
+
+enum Step {
+    Continue,
+    // A jump on a condition which isn't known: the condition, whether the
+    // jump is taken when it's non-zero, and where it goes
+    Fork(Arc<Expr>, bool, i64),
+    End(PathEnd),
+}
+
+impl SymbolicState {
+    pub fn new(program: Vec<i64>) -> Self {
+        SymbolicState {
+            memory: (0..)
+                .zip(program)
+                .filter(|&(_, value)| value != 0)
+                .map(|(address, value)| (address, Expr::constant(value)))
+                .collect(),
+            instruction_ptr: 0,
+            base_ptr: 0,
+            inputs: vec![],
+            inputs_read: 0,
+            conditions: vec![],
+            outputs: vec![],
+        }
+    }
+
+    // Makes the cell a variable, Var::Memory(address)
+    pub fn symbolic_mem(&mut self, address: i64) {
+        self.memory.insert(address, Expr::var(Var::Memory(address)));
+    }
+
+    pub fn set_mem(&mut self, address: i64, value: Arc<Expr>) {
+        self.memory.insert(address, value);
+    }
+
+    pub fn push_input(&mut self, value: i64) {
+        self.inputs.push(Expr::constant(value));
+    }
+
+    // Adds an input which is a variable, Var::Input(n) for the nth input
+    pub fn push_symbolic_input(&mut self) {
+        let n = self.inputs.len();
+        self.inputs.push(Expr::var(Var::Input(n)));
+    }
+
+    fn load(&self, address: i64) -> Result<Arc<Expr>, PathEnd> {
+        if address < 0 {
+            return Err(PathEnd::Fault(IntCodeErrorKind::NegativeAddress));
+        }
+        Ok(self
+            .memory
+            .get(&address)
+            .cloned()
+            .unwrap_or_else(|| Expr::constant(0)))
+    }
+
+    fn store(&mut self, address: i64, value: Arc<Expr>) {
+        self.memory.insert(address, value);
+    }
+
+    fn number(expr: Arc<Expr>) -> Result<i64, PathEnd> {
+        expr.as_const().ok_or(PathEnd::Unresolved(expr))
+    }
+
+    fn operand(&self, offset: i64) -> Result<i64, PathEnd> {
+        SymbolicState::number(self.load(self.instruction_ptr + offset)?)
+    }
+
+    fn parameter(&self, mode: u32, offset: i64) -> Result<Arc<Expr>, PathEnd> {
+        let word = self.load(self.instruction_ptr + offset)?;
+        let address = match mode {
+            0 => word,
+            1 => return Ok(word),
+            2 => Expr::sum(Expr::constant(self.base_ptr), word),
+            _ => return Err(PathEnd::Fault(IntCodeErrorKind::InvalidMode(mode))),
+        };
+        match address.as_const() {
+            Some(address) => self.load(address),
+            None => Ok(Arc::new(Expr::Load(address))),
+        }
+    }
+
+    fn parameter_address(&self, mode: u32, offset: i64) -> Result<i64, PathEnd> {
+        let pos = self.operand(offset)?;
+        let address = match mode {
+            0 => pos,
+            2 => self.base_ptr.wrapping_add(pos),
+            1 => return Err(PathEnd::Fault(IntCodeErrorKind::ImmediateModeWrite)),
+            _ => return Err(PathEnd::Fault(IntCodeErrorKind::InvalidMode(mode))),
+        };
+        if address < 0 {
+            return Err(PathEnd::Fault(IntCodeErrorKind::NegativeAddress));
+        }
+        Ok(address)
+    }
+
+    fn binary(
+        &mut self,
+        ins: &Instruction,
+        op: fn(Arc<Expr>, Arc<Expr>) -> Arc<Expr>,
+    ) -> Result<Step, PathEnd> {
+        let x = self.parameter(ins.mode1(), 1)?;
+        let y = self.parameter(ins.mode2(), 2)?;
+        let dest = self.parameter_address(ins.mode3(), 3)?;
+        self.store(dest, op(x, y));
+        self.instruction_ptr += 4;
+        Ok(Step::Continue)
+    }
+
+    fn step(&mut self) -> Result<Step, PathEnd> {
+        let raw = SymbolicState::number(self.load(self.instruction_ptr)?)?;
+        let ins = Instruction::try_from(raw).map_err(PathEnd::Fault)?;
+        match ins.typ() {
+            1 => self.binary(&ins, Expr::sum),
+            2 => self.binary(&ins, Expr::product),
+            3 => {
+                let dest = self.parameter_address(ins.mode1(), 1)?;
+                let value = self
+                    .inputs
+                    .get(self.inputs_read)
+                    .cloned()
+                    .ok_or(PathEnd::NeedsInput)?;
+                self.inputs_read += 1;
+                self.store(dest, value);
+                self.instruction_ptr += 2;
+                Ok(Step::Continue)
+            }
+            4 => {
+                let value = self.parameter(ins.mode1(), 1)?;
+                self.outputs.push(value);
+                self.instruction_ptr += 2;
+                Ok(Step::Continue)
+            }
+            typ @ (5 | 6) => {
+                let condition = self.parameter(ins.mode1(), 1)?;
+                let jump_if = typ == 5;
+                // The target is only read if the jump can be taken, like the VM
+                let taken = match condition.as_const() {
+                    Some(value) => (value != 0) == jump_if,
+                    None => match self.conditions.iter().find(|c| c.expr == condition) {
+                        Some(known) => known.holds == jump_if,
+                        None => {
+                            let target = SymbolicState::number(self.parameter(ins.mode2(), 2)?)?;
+                            return Ok(Step::Fork(condition, jump_if, target));
+                        }
+                    },
+                };
+                if taken {
+                    self.instruction_ptr = SymbolicState::number(self.parameter(ins.mode2(), 2)?)?;
+                } else {
+                    self.instruction_ptr += 3;
+                }
+                Ok(Step::Continue)
+            }
+            7 => self.binary(&ins, Expr::less_than),
+            8 => self.binary(&ins, Expr::equals),
+            9 => {
+                let amount = SymbolicState::number(self.parameter(ins.mode1(), 1)?)?;
+                self.base_ptr = self.base_ptr.wrapping_add(amount);
+                self.instruction_ptr += 2;
+                Ok(Step::Continue)
+            }
+            99 => Ok(Step::End(PathEnd::Halted)),
+            _ => Err(PathEnd::Fault(IntCodeErrorKind::InvalidOpcode(raw))),
+        }
+    }
+
+    fn finish(self, end: PathEnd) -> Path {
+        Path {
+            conditions: self.conditions,
+            outputs: self.outputs,
+            end,
+            instruction_ptr: self.instruction_ptr,
+            memory: self.memory,
+        }
+    }
+
+    // Follows every path from here, depth first, trying the way where the
+    // condition is zero first at each branch. Conditions already decided
+    // earlier on a path are reused rather than branched on again, but
+    // otherwise no attempt is made to spot paths which can't happen.
+    pub fn explore(&self, limits: &SymbolicLimits) -> Vec<Path> {
+        let mut paths = vec![];
+        let mut to_visit = vec![(self.clone(), 0)];
+        while let Some((mut state, mut steps)) = to_visit.pop() {
+            if paths.len() >= limits.max_paths {
+                break;
+            }
+            let end = loop {
+                if steps >= limits.fuel {
+                    break PathEnd::OutOfFuel;
+                }
+                steps += 1;
+                match state.step() {
+                    Ok(Step::Continue) => {}
+                    Ok(Step::End(end)) | Err(end) => break end,
+                    Ok(Step::Fork(expr, jump_if, target)) => {
+                        let mut taken = state.clone();
+                        taken.conditions.push(Condition {
+                            expr: expr.clone(),
+                            holds: jump_if,
+                        });
+                        taken.instruction_ptr = target;
+                        to_visit.push((taken, steps));
+                        state.conditions.push(Condition {
+                            expr,
+                            holds: !jump_if,
+                        });
+                        state.instruction_ptr += 3;
+                    }
+                }
+            };
+            paths.push(state.finish(end));
+        }
+        paths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::IntCodeState;
+    use super::*;
+
+    // Like day 2: works out mem[0] from a "noun" and "verb" at 1 and 2,
+    // after first reading through them as addresses
+    const GRAVITY_ASSIST: [i64; 24] = [
+        1, 0, 0, 3, 1, 1, 2, 3, 2, 1, 21, 22, 1, 22, 3, 23, 1, 23, 2, 0, 99, 7, 0, 0,
+    ];
+
+    fn run(program: &[i64], noun: i64, verb: i64) -> i64 {
+        let mut prog: IntCodeState = program.into();
+        prog.set_mem(1, noun);
+        prog.set_mem(2, verb);
+        prog.execute_until_halt_no_input();
+        prog.get_mem(0)
+    }
+
+    #[test]
+    fn test_formula_matches_vm() {
+        let mut state = SymbolicState::new(GRAVITY_ASSIST.to_vec());
+        state.symbolic_mem(1);
+        state.symbolic_mem(2);
+        let paths = state.explore(&SymbolicLimits::default());
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].end, PathEnd::Halted);
+        assert!(paths[0].conditions.is_empty());
+
+        let result = paths[0].get_mem(0);
+        let linear = result.linear().unwrap();
+        assert_eq!(linear.coefficient(Var::Memory(1)), 8);
+        assert_eq!(linear.coefficient(Var::Memory(2)), 2);
+        for (noun, verb) in [(0, 0), (12, 2), (5, 17)] {
+            let values = |var| match var {
+                Var::Memory(1) => Some(noun),
+                Var::Memory(2) => Some(verb),
+                _ => None,
+            };
+            let expected = run(&GRAVITY_ASSIST, noun, verb);
+            assert_eq!(result.eval(&values), Some(expected));
+        }
+        // The Load from reading through the noun and verb was overwritten
+        assert_eq!(
+            paths[0].get_mem(3),
+            Expr::sum(Expr::var(Var::Memory(1)), Expr::var(Var::Memory(2)))
+        );
+    }
+
+    #[test]
+    fn test_branches() {
+        // Outputs 1 if the input is less than 8, otherwise 2 * input
+        let program = vec![
+            3, 20, 1007, 20, 8, 21, 1005, 21, 16, 1002, 20, 2, 22, 4, 22, 99, 104, 1, 99,
+        ];
+        let mut state = SymbolicState::new(program.clone());
+        state.push_symbolic_input();
+        let paths = state.explore(&SymbolicLimits::default());
+        assert_eq!(paths.len(), 2);
+
+        let input = Expr::var(Var::Input(0));
+        let less = Expr::less_than(input.clone(), Expr::constant(8));
+        assert_eq!(
+            paths[0].conditions,
+            vec![Condition {
+                expr: less.clone(),
+                holds: false
+            }]
+        );
+        assert_eq!(
+            paths[0].outputs,
+            vec![Expr::product(input, Expr::constant(2))]
+        );
+        assert_eq!(paths[1].conditions[0].to_string(), "(in_0 < 8) != 0");
+        assert_eq!(paths[1].outputs, vec![Expr::constant(1)]);
+
+        for value in [-3, 7, 8, 20] {
+            let values = |_| Some(value);
+            let path = paths
+                .iter()
+                .find(|p| p.is_taken(&values) == Some(true))
+                .unwrap();
+            let mut prog: IntCodeState = program.clone().into();
+            prog.execute_until_halt(|_| Some(value));
+            assert_eq!(path.outputs[0].eval(&values), prog.out_buffer.pop_front());
+        }
+    }
+
+    #[test]
+    fn test_limits_and_ends() {
+        // Jumps back to read another input if the first isn't zero
+        let program = vec![3, 7, 1005, 7, 0, 99, 0, 0];
+        let mut state = SymbolicState::new(program);
+        state.push_symbolic_input();
+        let paths = state.explore(&SymbolicLimits::default());
+        assert_eq!(paths.len(), 2);
+        assert_eq!(paths[0].end, PathEnd::Halted);
+        assert_eq!(paths[1].end, PathEnd::NeedsInput);
+
+        let state = SymbolicState::new(vec![1105, 1, 0]);
+        let paths = state.explore(&SymbolicLimits {
+            fuel: 10,
+            max_paths: 1,
+        });
+        assert_eq!(paths[0].end, PathEnd::OutOfFuel);
+
+        let mut state = SymbolicState::new(vec![3, 5, 1, 0, 0, 0, 99]);
+        state.push_symbolic_input();
+        let paths = state.explore(&SymbolicLimits::default());
+        assert_eq!(paths[0].end, PathEnd::Unresolved(Expr::var(Var::Input(0))));
+
+        // The target of a jump which isn't taken is never read
+        let state = SymbolicState::new(vec![105, 0, -1, 99]);
+        let paths = state.explore(&SymbolicLimits::default());
+        assert_eq!(paths[0].end, PathEnd::Halted);
+
+        let state = SymbolicState::new(vec![4, -1, 99]);
+        let paths = state.explore(&SymbolicLimits::default());
+        assert_eq!(
+            paths[0].end,
+            PathEnd::Fault(IntCodeErrorKind::NegativeAddress)
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let x = Expr::var(Var::Memory(1));
+        let y = Expr::var(Var::Input(0));
+        let expr = Expr::sum(
+            Expr::product(Expr::product(x.clone(), y.clone()), Expr::constant(3)),
+            Expr::equals(x.clone(), Expr::constant(0)),
+        );
+        assert_eq!(expr.to_string(), "((mem_1 * in_0) * 3 + (mem_1 == 0))");
+        assert_eq!(Expr::sum(x.clone(), Expr::constant(0)), x);
+        assert_eq!(
+            Expr::product(x.clone(), Expr::constant(0)),
+            Expr::constant(0)
+        );
+        assert_eq!(Expr::equals(y.clone(), y), Expr::constant(1));
+    }
+}
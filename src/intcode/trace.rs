@@ -0,0 +1,237 @@
+use super::disasm::DecodedInstruction;
+use super::IntCodeState;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct MemoryWrite {
+    pub address: i64,
+    pub old: i64,
+    pub new: i64,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct TraceRecord {
+    pub instruction_ptr: i64,
+    pub instruction: DecodedInstruction,
+    // Value of each operand as the instruction saw it. For the operand
+    // being written to, this is the address written. A jump which isn't
+    // taken never sees its target, so only has its condition.
+    pub values: Vec<i64>,
+    pub write: Option<MemoryWrite>,
+    pub base_change: Option<(i64, i64)>,
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let values = self
+            .values
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(
+            f,
+            "{:>5}: {:<28} ({})",
+            self.instruction_ptr,
+            self.instruction.to_string(),
+            values
+        )?;
+        if let Some(write) = self.write {
+            write!(f, " [{}] {} -> {}", write.address, write.old, write.new)?;
+        }
+        if let Some((old, new)) = self.base_change {
+            write!(f, " base {} -> {}", old, new)?;
+        }
+        Ok(())
+    }
+}
+
+pub trait TraceSink {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()>;
+}
+
+// Keeps the most recent records in memory. Clones share the same buffer,
+// so keep a clone to read the records back while the VM owns the sink.
+#[derive(Debug, Clone)]
+pub struct TraceBuffer {
+    capacity: usize,
+    records: Arc<Mutex<VecDeque<TraceRecord>>>,
+}
+
+impl TraceBuffer {
+    pub fn new(capacity: usize) -> Self {
+        TraceBuffer {
+            capacity,
+            records: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    pub fn records(&self) -> Vec<TraceRecord> {
+        self.records
+            .lock()
+            .expect("trace buffer poisoned")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.records.lock().expect("trace buffer poisoned").clear();
+    }
+}
+
+impl TraceSink for TraceBuffer {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let mut records = self.records.lock().expect("trace buffer poisoned");
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        if self.capacity > 0 {
+            records.push_back(record.clone());
+        }
+        Ok(())
+    }
+}
+
+// Writes one line per record, e.g. to a file for diffing against another run
+pub struct TraceWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(writer: W) -> Self {
+        TraceWriter { writer }
+    }
+}
+
+impl<W: Write> TraceSink for TraceWriter<W> {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        writeln!(self.writer, "{}", record)
+    }
+}
+
+// Index of the first record where two traces differ, if they differ at all
+pub fn first_divergence(a: &[TraceRecord], b: &[TraceRecord]) -> Option<usize> {
+    a.iter()
+        .zip(b.iter())
+        .position(|(x, y)| x != y)
+        .or_else(|| (a.len() != b.len()).then_some(a.len().min(b.len())))
+}
+
+impl IntCodeState {
+    pub fn start_trace<S: TraceSink + Send + Sync + 'static>(&mut self, sink: S) {
+        let inner = self.instruments.get_mut();
+        inner.trace = Some(Box::new(sink));
+        inner.trace_error = None;
+    }
+
+    pub fn stop_trace(&mut self) -> Option<Box<dyn TraceSink + Send + Sync>> {
+        let sink = self.instruments.get_mut().trace.take();
+        self.instruments.prune();
+        sink
+    }
+
+    // Tracing stops if the sink fails; the error is kept here
+    pub fn take_trace_error(&mut self) -> Option<io::Error> {
+        let error = self.instruments.get_mut().trace_error.take();
+        self.instruments.prune();
+        error
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.instruments.get().is_some_and(|i| i.trace.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::disasm::Opcode;
+    use super::*;
+
+    #[test]
+    fn test_trace_records() {
+        let mut prog: IntCodeState = vec![109, 10, 3, 0, 21001, 0, 1, 1, 204, 1, 99].into();
+        let buffer = TraceBuffer::new(100);
+        prog.start_trace(buffer.clone());
+        prog.execute_until_halt(|_| Some(41));
+
+        let records = buffer.records();
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[0].base_change, Some((0, 10)));
+        assert_eq!(
+            records[1].write,
+            Some(MemoryWrite {
+                address: 0,
+                old: 109,
+                new: 41
+            })
+        );
+        assert_eq!(records[2].instruction.opcode, Opcode::Add);
+        assert_eq!(records[2].values, vec![41, 1, 11]);
+        assert_eq!(records[2].write.map(|w| w.new), Some(42));
+        assert_eq!(records[3].values, vec![42]);
+        assert_eq!(
+            records[2].to_string(),
+            "    4: add 0, #1, @1                (41, 1, 11) [11] 0 -> 42"
+        );
+    }
+
+    #[test]
+    fn test_ring_buffer_keeps_latest() {
+        let mut prog: IntCodeState = vec![104, 1, 104, 2, 104, 3, 99].into();
+        let buffer = TraceBuffer::new(2);
+        prog.start_trace(buffer.clone());
+        prog.execute_until_halt_no_input();
+        let ips = buffer
+            .records()
+            .iter()
+            .map(|r| r.instruction_ptr)
+            .collect::<Vec<_>>();
+        assert_eq!(ips, vec![4, 6]);
+    }
+
+    #[test]
+    fn test_diff_runs() {
+        let program = vec![3, 9, 8, 9, 10, 9, 4, 9, 99, -1, 8];
+        let traces = [7, 8].map(|inp| {
+            let mut prog: IntCodeState = program.clone().into();
+            let buffer = TraceBuffer::new(100);
+            prog.start_trace(buffer.clone());
+            prog.execute_until_halt(|_| Some(inp));
+            buffer.records()
+        });
+        assert_eq!(first_divergence(&traces[0], &traces[0]), None);
+        assert_eq!(first_divergence(&traces[0], &traces[1]), Some(0));
+        assert_eq!(first_divergence(&traces[0][1..], &traces[1][1..]), Some(0));
+    }
+
+    #[test]
+    fn test_trace_to_writer_and_stop() {
+        let mut prog: IntCodeState = vec![1101, 1, 2, 0, 99].into();
+        prog.start_trace(TraceWriter::new(vec![]));
+        assert!(prog.is_tracing());
+        prog.execute_until_halt_no_input();
+        assert!(prog.stop_trace().is_some());
+        assert!(!prog.is_tracing());
+        assert!(!prog.instruments.is_active());
+    }
+
+    #[test]
+    fn test_failing_sink_stops_trace() {
+        struct Failing;
+        impl TraceSink for Failing {
+            fn record(&mut self, _: &TraceRecord) -> io::Result<()> {
+                Err(io::Error::other("disk full"))
+            }
+        }
+        let mut prog: IntCodeState = vec![1101, 1, 2, 0, 99].into();
+        prog.start_trace(Failing);
+        prog.execute_until_halt_no_input();
+        assert!(!prog.is_tracing());
+        assert_eq!(prog.take_trace_error().unwrap().to_string(), "disk full");
+        assert_eq!(prog.get_mem(0), 3);
+    }
+}
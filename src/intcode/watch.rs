@@ -0,0 +1,249 @@
+use super::IntCodeState;
+use std::ops::{Bound, RangeBounds};
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct MemoryAccess {
+    // The instruction which made the access
+    pub instruction_ptr: i64,
+    pub address: i64,
+    pub kind: AccessKind,
+    // Value before the access, which is the same as `new` for reads
+    pub old: i64,
+    pub new: i64,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct WatchId(u64);
+
+type Hook = Box<dyn FnMut(&MemoryAccess) + Send + Sync>;
+
+struct Watch {
+    id: WatchId,
+    kind: AccessKind,
+    // Half open, so [start, end)
+    start: i64,
+    end: i64,
+    hook: Hook,
+}
+
+#[derive(Default)]
+pub(super) struct Watches {
+    next_id: u64,
+    watches: Vec<Watch>,
+}
+
+impl Watches {
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    pub fn notify(&mut self, access: &MemoryAccess) {
+        for watch in self.watches.iter_mut() {
+            if watch.kind == access.kind && (watch.start..watch.end).contains(&access.address) {
+                (watch.hook)(access);
+            }
+        }
+    }
+}
+
+fn to_half_open(range: impl RangeBounds<i64>) -> (i64, i64) {
+    let start = match range.start_bound() {
+        Bound::Included(&start) => start,
+        Bound::Excluded(&start) => start.saturating_add(1),
+        Bound::Unbounded => i64::MIN,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(&end) => end.saturating_add(1),
+        Bound::Excluded(&end) => end,
+        Bound::Unbounded => i64::MAX,
+    };
+    (start, end)
+}
+
+impl IntCodeState {
+    // Calls `hook` whenever the program reads or writes (depending on
+    // `kind`) an address in `range`. Reads are of operands in position or
+    // relative mode; fetching the instruction itself doesn't count. Only
+    // accesses made by executing instructions are seen, so changes made
+    // with set_mem don't fire hooks, and get_mem and set_mem cost nothing
+    // extra. Instructions which fail don't fire hooks, and neither does an
+    // input instruction which is waiting for input.
+    pub fn watch<R, F>(&mut self, kind: AccessKind, range: R, hook: F) -> WatchId
+    where
+        R: RangeBounds<i64>,
+        F: FnMut(&MemoryAccess) + Send + Sync + 'static,
+    {
+        let (start, end) = to_half_open(range);
+        let watches = self
+            .instruments
+            .get_mut()
+            .watches
+            .get_or_insert_with(Watches::default);
+        let id = WatchId(watches.next_id);
+        watches.next_id += 1;
+        watches.watches.push(Watch {
+            id,
+            kind,
+            start,
+            end,
+            hook: Box::new(hook),
+        });
+        id
+    }
+
+    // Returns false if there was no such watch
+    pub fn unwatch(&mut self, id: WatchId) -> bool {
+        let inner = self.instruments.get_mut();
+        let Some(watches) = inner.watches.as_mut() else {
+            self.instruments.prune();
+            return false;
+        };
+        let before = watches.watches.len();
+        watches.watches.retain(|w| w.id != id);
+        let removed = watches.watches.len() != before;
+        if watches.is_empty() {
+            inner.watches = None;
+        }
+        self.instruments.prune();
+        removed
+    }
+
+    pub fn is_watching(&self) -> bool {
+        self.instruments.get().is_some_and(|i| i.watches.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::IntCodeEvent;
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn recorder() -> (
+        Arc<Mutex<Vec<MemoryAccess>>>,
+        impl FnMut(&MemoryAccess) + Send + Sync + 'static,
+    ) {
+        let log = Arc::new(Mutex::new(vec![]));
+        let hook_log = log.clone();
+        (log, move |access: &MemoryAccess| {
+            hook_log.lock().unwrap().push(*access)
+        })
+    }
+
+    #[test]
+    fn test_reads_and_writes() {
+        // mem[20] = mem[20] + mem[21], twice, then output mem[20]
+        let mut program = vec![1, 20, 21, 20, 1, 20, 21, 20, 4, 20, 99];
+        program.resize(22, 0);
+        program[20] = 3;
+        program[21] = 4;
+        let mut prog: IntCodeState = program.into();
+        let (writes, hook) = recorder();
+        prog.watch(AccessKind::Write, 20..=20, hook);
+        let (reads, hook) = recorder();
+        prog.watch(AccessKind::Read, 21.., hook);
+        prog.execute_until_halt_no_input();
+
+        let writes = writes.lock().unwrap();
+        assert_eq!(
+            *writes,
+            vec![
+                MemoryAccess {
+                    instruction_ptr: 0,
+                    address: 20,
+                    kind: AccessKind::Write,
+                    old: 3,
+                    new: 7
+                },
+                MemoryAccess {
+                    instruction_ptr: 4,
+                    address: 20,
+                    kind: AccessKind::Write,
+                    old: 7,
+                    new: 11
+                },
+            ]
+        );
+        let reads = reads.lock().unwrap();
+        assert_eq!(reads.len(), 2);
+        assert!(reads.iter().all(|r| r.address == 21 && r.new == 4));
+        assert_eq!(prog.out_buffer.pop_front(), Some(11));
+    }
+
+    #[test]
+    fn test_relative_and_input() {
+        let mut prog: IntCodeState = vec![109, 5, 203, 3, 99, 0, 0, 0, 0].into();
+        let (writes, hook) = recorder();
+        prog.watch(AccessKind::Write, 6..10, hook);
+        assert_eq!(prog.run_until_event(), IntCodeEvent::NeedsInput);
+        assert!(writes.lock().unwrap().is_empty());
+
+        prog.in_buffer.push_back(42);
+        assert_eq!(prog.run_until_event(), IntCodeEvent::Halted);
+        let writes = writes.lock().unwrap();
+        assert_eq!(writes.len(), 1);
+        assert_eq!((writes[0].address, writes[0].new), (8, 42));
+    }
+
+    #[test]
+    fn test_find_counter() {
+        // Counts down from 5, like a score or loop variable kept in memory
+        let mut prog: IntCodeState = vec![1001, 11, -1, 11, 1005, 11, 0, 104, 0, 99, 0, 5].into();
+        let (writes, hook) = recorder();
+        prog.watch(AccessKind::Write, .., hook);
+        prog.execute_until_halt_no_input();
+        let values = writes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|w| w.address == 11)
+            .map(|w| w.new)
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn test_untaken_jump_target() {
+        // jf 7, 8 falls through as mem[7] is 1, so only jt 7, 8 reads mem[8]
+        let mut prog: IntCodeState = vec![6, 7, 8, 5, 7, 8, 99, 1, 6].into();
+        let (reads, hook) = recorder();
+        prog.watch(AccessKind::Read, 8..=8, hook);
+        prog.enable_history(10);
+        prog.execute_until_halt_no_input();
+        let reads = reads.lock().unwrap();
+        assert_eq!(reads.len(), 1);
+        assert_eq!((reads[0].instruction_ptr, reads[0].new), (3, 6));
+        assert_eq!(prog.history_len(), 2);
+    }
+
+    #[test]
+    fn test_unwatch() {
+        let mut prog: IntCodeState = vec![1101, 1, 2, 5, 99, 0].into();
+        let (writes, hook) = recorder();
+        let id = prog.watch(AccessKind::Write, 5..6, hook);
+        assert!(prog.is_watching());
+        assert!(prog.unwatch(id));
+        assert!(!prog.unwatch(id));
+        assert!(!prog.is_watching());
+        assert!(!prog.instruments.is_active());
+        prog.execute_until_halt_no_input();
+        assert!(writes.lock().unwrap().is_empty());
+        assert_eq!(prog.get_mem(5), 3);
+    }
+
+    #[test]
+    fn test_set_mem_not_seen() {
+        let mut prog: IntCodeState = vec![99, 0].into();
+        let (writes, hook) = recorder();
+        prog.watch(AccessKind::Write, .., hook);
+        prog.set_mem(1, 5);
+        prog.execute_until_halt_no_input();
+        assert!(writes.lock().unwrap().is_empty());
+    }
+}
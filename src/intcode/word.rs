@@ -0,0 +1,295 @@
+use backend::Backend;
+use num::{BigInt, ToPrimitive};
+use std::fmt;
+use std::str::FromStr;
+
+// Sealed in a private module, so the VM's private types in its signatures
+// can't be reached from outside the crate
+#[allow(private_interfaces)]
+mod backend {
+    use super::super::{Instruction, IntCodeError, IntCodeErrorKind, IntCodeState, Step};
+    use super::Checked;
+    use num::BigInt;
+
+    // How IntCodeState runs instructions with each word. Private, so only
+    // the words here can be used.
+    pub trait Backend: Sized {
+        fn step<F>(state: &mut IntCodeState<Self>, input_handler: F) -> Result<Step, IntCodeError>
+        where
+            F: FnMut(&mut IntCodeState<Self>) -> Option<Self>;
+
+        fn unknown_opcode(
+            state: &mut IntCodeState<Self>,
+            instruction: &Instruction,
+        ) -> Result<Step, IntCodeError>;
+    }
+
+    impl Backend for i64 {
+        #[inline]
+        fn step<F>(state: &mut IntCodeState, input_handler: F) -> Result<Step, IntCodeError>
+        where
+            F: FnMut(&mut IntCodeState) -> Option<i64>,
+        {
+            if state.instruments.is_active() {
+                state.step_instrumented(input_handler)
+            } else {
+                state.step_plain(input_handler)
+            }
+        }
+
+        fn unknown_opcode(
+            state: &mut IntCodeState,
+            instruction: &Instruction,
+        ) -> Result<Step, IntCodeError> {
+            state.step_custom(instruction)
+        }
+    }
+
+    // Every other word decodes each instruction from scratch
+    macro_rules! generic_backend {
+        ($t:ty) => {
+            impl Backend for $t {
+                fn step<F>(
+                    state: &mut IntCodeState<Self>,
+                    input_handler: F,
+                ) -> Result<Step, IntCodeError>
+                where
+                    F: FnMut(&mut IntCodeState<Self>) -> Option<Self>,
+                {
+                    state.step_generic(input_handler)
+                }
+
+                fn unknown_opcode(
+                    state: &mut IntCodeState<Self>,
+                    instruction: &Instruction,
+                ) -> Result<Step, IntCodeError> {
+                    let kind = IntCodeErrorKind::InvalidOpcode(instruction.typ() as i64);
+                    Err(state.fault(kind, None))
+                }
+            }
+        };
+    }
+
+    generic_backend!(i128);
+    generic_backend!(Checked<i64>);
+    generic_backend!(Checked<i128>);
+    generic_backend!(BigInt);
+}
+
+// What the VM computes with. Addresses, the instruction pointer and the
+// relative base stay i64 whatever the word, so words used as addresses
+// must fit in one.
+pub trait Word: Backend + Clone + Ord + fmt::Debug + fmt::Display + FromStr {
+    fn from_i64(value: i64) -> Self;
+
+    // None if the value doesn't fit
+    fn to_i64(&self) -> Option<i64>;
+
+    // None if the result can't be represented, which the VM reports as
+    // IntCodeErrorKind::Overflow
+    fn checked_add(&self, other: &Self) -> Option<Self>;
+
+    fn checked_mul(&self, other: &Self) -> Option<Self>;
+}
+
+// Plain machine integers wrap on overflow. Built-in instructions on i64
+// are pre-decoded and use plain arithmetic, so wrap in release builds and
+// panic in debug builds, as they always have.
+macro_rules! wrapping_word {
+    ($t:ty) => {
+        impl Word for $t {
+            fn from_i64(value: i64) -> Self {
+                value as $t
+            }
+
+            fn to_i64(&self) -> Option<i64> {
+                i64::try_from(*self).ok()
+            }
+
+            fn checked_add(&self, other: &Self) -> Option<Self> {
+                Some(self.wrapping_add(*other))
+            }
+
+            fn checked_mul(&self, other: &Self) -> Option<Self> {
+                Some(self.wrapping_mul(*other))
+            }
+        }
+    };
+}
+
+wrapping_word!(i64);
+wrapping_word!(i128);
+
+// A machine integer which reports overflow rather than wrapping
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Clone, Copy, Default)]
+pub struct Checked<T>(pub T);
+
+impl<T: fmt::Display> fmt::Display for Checked<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<T: FromStr> FromStr for Checked<T> {
+    type Err = T::Err;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Checked)
+    }
+}
+
+macro_rules! checked_word {
+    ($t:ty) => {
+        impl Word for Checked<$t> {
+            fn from_i64(value: i64) -> Self {
+                Checked(value as $t)
+            }
+
+            fn to_i64(&self) -> Option<i64> {
+                i64::try_from(self.0).ok()
+            }
+
+            fn checked_add(&self, other: &Self) -> Option<Self> {
+                self.0.checked_add(other.0).map(Checked)
+            }
+
+            fn checked_mul(&self, other: &Self) -> Option<Self> {
+                self.0.checked_mul(other.0).map(Checked)
+            }
+        }
+    };
+}
+
+checked_word!(i64);
+checked_word!(i128);
+
+impl Word for BigInt {
+    fn from_i64(value: i64) -> Self {
+        BigInt::from(value)
+    }
+
+    fn to_i64(&self) -> Option<i64> {
+        ToPrimitive::to_i64(self)
+    }
+
+    fn checked_add(&self, other: &Self) -> Option<Self> {
+        Some(self + other)
+    }
+
+    fn checked_mul(&self, other: &Self) -> Option<Self> {
+        Some(self * other)
+    }
+}
+
+pub fn parse_words<W: Word>(inp: &str) -> Result<Vec<W>, W::Err> {
+    inp.trim().split(',').map(|s| s.trim().parse()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::super::{IntCodeError, IntCodeErrorKind, IntCodeEvent, IntCodeState, MemoryModel};
+    use super::*;
+
+    // Squares its input, forever
+    fn squares() -> Vec<i64> {
+        assemble(
+            "
+            loop:   in x
+                    mul x, x, y
+                    out y
+                    jt #1, #loop
+            x:      data 0
+            y:      data 0
+            ",
+        )
+        .unwrap()
+    }
+
+    fn square<W: Word>(value: W) -> Result<IntCodeEvent<W>, IntCodeError> {
+        let program = squares().into_iter().map(W::from_i64).collect::<Vec<_>>();
+        let mut vm = IntCodeState::from(program);
+        vm.in_buffer.push_back(value);
+        vm.try_run_until_event()
+    }
+
+    #[test]
+    fn test_backends() {
+        let big = 1i64 << 40;
+        // Plain i64 runs the pre-decoded instructions, which only wrap in
+        // release builds
+        assert_eq!(square(-7i64).unwrap(), IntCodeEvent::Output(49));
+        assert_eq!(
+            square(big as i128).unwrap(),
+            IntCodeEvent::Output(1i128 << 80)
+        );
+        assert_eq!(
+            square(Checked(-7i64)).unwrap(),
+            IntCodeEvent::Output(Checked(49))
+        );
+        let error = square(Checked(big)).unwrap_err();
+        assert_eq!(error.kind, IntCodeErrorKind::Overflow);
+        assert_eq!(error.instruction, Some(2));
+        assert_eq!(
+            square(Checked(big as i128)).unwrap(),
+            IntCodeEvent::Output(Checked(1 << 80))
+        );
+
+        let huge = BigInt::from(3).pow(100);
+        assert_eq!(
+            square(huge.clone()).unwrap(),
+            IntCodeEvent::Output(huge.pow(2))
+        );
+    }
+
+    #[test]
+    fn test_address_overflow() {
+        // Moves the base past i64::MAX, then reads relative to a base at it
+        for program in [
+            vec![109, i64::MAX, 109, 1, 99],
+            vec![109, i64::MAX, 204, 1, 99],
+        ] {
+            let mut prog: IntCodeState = program.clone().into();
+            let error = prog.try_execute_until_halt(|_| None).unwrap_err();
+            assert_eq!(
+                (error.kind, error.instruction_ptr),
+                (IntCodeErrorKind::Overflow, 2)
+            );
+            assert_eq!(prog.base_ptr(), i64::MAX);
+
+            let program = program.into_iter().map(Checked).collect::<Vec<_>>();
+            let mut prog = IntCodeState::from(program);
+            let error = prog.try_execute_until_halt(|_| None).unwrap_err();
+            assert_eq!(
+                (error.kind, error.instruction_ptr),
+                (IntCodeErrorKind::Overflow, 2)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_and_run() {
+        let text = squares().iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        let program = parse_words(&text.join(",")).unwrap();
+        let mut vm: IntCodeState<BigInt> = IntCodeState::new(program, MemoryModel::Paged);
+        vm.in_buffer.extend([2, 3].map(BigInt::from));
+        assert_eq!(vm.run_until_event(), IntCodeEvent::Output(BigInt::from(4)));
+        assert_eq!(vm.run_until_event(), IntCodeEvent::Output(BigInt::from(9)));
+        assert_eq!(vm.run_until_event(), IntCodeEvent::NeedsInput);
+
+        // Compares, jumps and relative addressing, from the day 9 examples
+        let quine = "109,1,204,-1,1001,100,1,100,1008,100,16,101,1006,101,0,99";
+        let expected: Vec<Checked<i64>> = parse_words(quine).unwrap();
+        let mut vm = IntCodeState::new(expected.clone(), MemoryModel::Sparse);
+        vm.execute_until_halt_no_input();
+        assert_eq!(Vec::from(vm.out_buffer.clone()), expected);
+    }
+
+    #[test]
+    fn test_address_out_of_range() {
+        let mut vm: IntCodeState<i128> = vec![4, 1i128 << 70, 99].into();
+        let error = vm.try_execute_until_halt(|_| None).unwrap_err();
+        assert_eq!(error.kind, IntCodeErrorKind::OutOfBounds);
+        assert_eq!(error.address, None);
+    }
+}